@@ -15,23 +15,52 @@
 // You should have received a copy of the GNU General Public License
 // along with genuki.  If not, see <http://www.gnu.org/licenses/>.
 
+use std::collections::BTreeMap;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
 
-use anyhow::Error;
+use anyhow::{anyhow, Error};
 use clap::ArgMatches;
 
 use crate::config::Config;
+use crate::error::{AppError, ErrorClass};
+use crate::logger;
+use crate::manifest::{inputs, Manifest, Record};
+use crate::uki::Plan;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Action {
+    Generate,
+    Remove,
+    List,
+}
+
+/// What was done with an entry, e.g. Done("built") or Skipped("up to date")
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Outcome {
+    Done(&'static str),
+    Skipped(&'static str),
+}
+
+type Results = Vec<(String, Result<Outcome, AppError>)>;
+
+#[derive(Debug)]
 pub struct App {
     config: Config,
-    remove: bool,
+    action: Action,
+    dry_run: bool,
+    force: bool,
+    keep_going: bool,
+    jobs: usize,
+    manifest: Mutex<Manifest>,
     to_build: Vec<(String, String)>,
 }
 
 impl App {
     pub fn from_matches(matches: ArgMatches) -> Result<Self, Error> {
-        let config = Config::from_matches(&matches)?;
+        let config = Config::from_matches(&matches).map_err(AppError::from_config)?;
 
         let mut all_entries = Vec::new();
         for (name, kernel) in &config.kernels {
@@ -40,10 +69,23 @@ impl App {
             }
         }
 
+        all_entries.sort();
+
+        let action = if matches.subcommand_matches("list").is_some() {
+            Action::List
+        } else if matches.is_present("remove") {
+            Action::Remove
+        } else {
+            Action::Generate
+        };
+
         let to_build: Vec<_> = match matches.occurrences_of("all") {
+            _ if action == Action::List => all_entries,
+
             0 => {
                 let regexes: Vec<_> = matches.values_of("entries").unwrap().collect();
-                let regexes = regex::RegexSet::new(&regexes)?;
+                let regexes =
+                    regex::RegexSet::new(&regexes).map_err(|e| AppError::Config(e.into()))?;
 
                 all_entries
                     .iter()
@@ -64,115 +106,342 @@ impl App {
             _ => all_entries,
         };
 
-        let remove = matches.is_present("remove");
+        let dry_run = matches.is_present("dry-run");
+        let force = matches.is_present("force");
+        let keep_going = matches.is_present("keep-going");
+        let jobs = match matches.value_of("jobs").unwrap().parse()? {
+            0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+            jobs => jobs,
+        };
+        let manifest = Manifest::load(matches.value_of("state-dir").unwrap());
 
         Ok(Self {
             config,
-            remove,
+            action,
+            dry_run,
+            force,
+            keep_going,
+            jobs,
+            manifest: Mutex::new(manifest),
             to_build,
         })
     }
 
-    pub fn run(&self) -> Result<(), Error> {
-        let action = if self.remove {
-            Self::remove_uki
+    // Entries writing the same image would overwrite each other (at the
+    // same time when building in parallel), and confuse the manifest, so
+    // none of them gets built. Maps each of them to the output and all the
+    // entries sharing it
+    fn shared_outputs(&self) -> BTreeMap<String, (PathBuf, Vec<String>)> {
+        let mut outputs: BTreeMap<PathBuf, Vec<String>> = BTreeMap::new();
+        for (kernel, flavor) in &self.to_build {
+            // Entries without an output fail on their own later
+            if let Ok(output) = self.config.output_path(kernel, flavor) {
+                outputs
+                    .entry(output)
+                    .or_default()
+                    .push(format!("{}.{}", kernel, flavor));
+            }
+        }
+
+        let mut shared = BTreeMap::new();
+        for (output, entries) in outputs.into_iter().filter(|(_, e)| e.len() > 1) {
+            for entry in &entries {
+                shared.insert(entry.clone(), (output.clone(), entries.clone()));
+            }
+        }
+
+        shared
+    }
+
+    pub fn run(&self) -> Result<(), AppError> {
+        let shared = match self.action {
+            Action::Generate => self.shared_outputs(),
+            _ => BTreeMap::new(),
+        };
+
+        let action = match self.action {
+            Action::Generate => Self::generate_uki,
+            Action::Remove => Self::remove_uki,
+            Action::List => Self::list_entry,
+        };
+
+        // Every error says which entry it comes from
+        let action = |kernel: &str, flavor: &str| {
+            let entry = format!("{}.{}", kernel, flavor);
+            let result = match shared.get(&entry) {
+                Some((output, entries)) => Err(AppError::Config(anyhow!(
+                    "{} write the same output \"{}\"",
+                    entries.join(", "),
+                    output.to_string_lossy()
+                ))),
+                None => action(self, kernel, flavor),
+            };
+
+            result.map_err(|e| e.context(entry, None))
+        };
+
+        // Listing and dry runs only print, keep them in order
+        let parallel = self.jobs > 1 && self.action != Action::List && !self.dry_run;
+        let results = if parallel {
+            self.run_parallel(action)
         } else {
-            Self::generate_uki
+            let mut results = Vec::with_capacity(self.to_build.len());
+            for (kernel, flavor) in &self.to_build {
+                let result = action(kernel, flavor);
+                match &result {
+                    Err(e) if self.keep_going => log::error!("{}", e),
+                    Err(_) => return result.map(|_| ()),
+                    Ok(_) => {}
+                }
+
+                results.push((format!("{}.{}", kernel, flavor), result));
+            }
+
+            results
         };
 
-        for (kernel, flavor) in &self.to_build {
-            action(&self, kernel, flavor)?;
+        // Parallel builds always go through every entry
+        if (self.keep_going || parallel) && self.action != Action::List {
+            print_summary(&results);
+        }
+
+        let failures: Vec<_> = results
+            .iter()
+            .filter_map(|(_, r)| r.as_ref().err())
+            .collect();
+        if failures.is_empty() {
+            return Ok(());
+        }
+
+        // Only a single class of failure says something about all of them
+        let class = failures[0].class();
+        let same_class = failures.iter().all(|e| e.class() == class);
+
+        Err(AppError::Failed {
+            failed: failures.len(),
+            total: results.len(),
+            class: if same_class { class } else { ErrorClass::Other },
+        })
+    }
+
+    fn run_parallel(
+        &self,
+        action: impl Fn(&str, &str) -> Result<Outcome, AppError> + Sync,
+    ) -> Results {
+        let next = AtomicUsize::new(0);
+        let results = Mutex::new(Vec::with_capacity(self.to_build.len()));
+
+        std::thread::scope(|scope| {
+            for _ in 0..self.jobs.min(self.to_build.len()) {
+                scope.spawn(|| loop {
+                    let index = next.fetch_add(1, Ordering::SeqCst);
+                    let (kernel, flavor) = match self.to_build.get(index) {
+                        Some(entry) => entry,
+                        None => break,
+                    };
+
+                    let result = logger::grouped(|| {
+                        action(kernel, flavor).map_err(|e| {
+                            log::error!("{}", e);
+                            e
+                        })
+                    });
+
+                    let entry = format!("{}.{}", kernel, flavor);
+                    results.lock().unwrap().push((index, entry, result));
+                });
+            }
+        });
+
+        // Back in the same order as they were given
+        let mut results = results.into_inner().unwrap();
+        results.sort_by_key(|(index, _, _)| *index);
+        results
+            .into_iter()
+            .map(|(_, entry, result)| (entry, result))
+            .collect()
+    }
+
+    fn list_entry(&self, kernel: &str, flavor: &str) -> Result<Outcome, AppError> {
+        let enabled = if self.config.is_enabled(kernel, flavor) {
+            "enabled"
+        } else {
+            "disabled"
+        };
+
+        println!("{}.{} ({})", kernel, flavor, enabled);
+
+        // Resolution errors are shown in place, so a single broken
+        // entry doesn't hide the rest of them
+        let show = |field: &str, value: Result<String, AppError>| match value {
+            Ok(value) => println!("  {:<8} {}", format!("{}:", field), value),
+            Err(e) => println!("  {:<8} error: {}", format!("{}:", field), e.detail()),
+        };
+
+        let display = |path: PathBuf| path.to_string_lossy().to_string();
+        if let Some(version) = self.config.version(kernel, flavor) {
+            show("version", Ok(version));
         }
 
-        Ok(())
+        show("linux", self.config.linux_path(kernel, flavor).map(display));
+        if let Some(cmdline) = self.config.cmdline(kernel, flavor).transpose() {
+            show("cmdline", cmdline.map(|c| c.to_string()));
+        }
+        show(
+            "initrd",
+            self.config
+                .initrd_source(kernel, flavor)
+                .map(|s| s.describe()),
+        );
+        show(
+            "efistub",
+            self.config.efistub_path(kernel, flavor).map(display),
+        );
+        show(
+            "output",
+            self.config.output_path(kernel, flavor).map(|output| {
+                let status = match output.metadata().and_then(|m| m.modified()) {
+                    Ok(modified) => {
+                        let age = SystemTime::now()
+                            .duration_since(modified)
+                            .unwrap_or_default();
+                        format!("exists, built {} ago", format_age(age))
+                    }
+                    Err(_) => "missing".to_string(),
+                };
+
+                format!("{} ({})", output.to_string_lossy(), status)
+            }),
+        );
+
+        Ok(Outcome::Done("listed"))
     }
 
-    fn remove_uki(&self, kernel: &str, flavor: &str) -> Result<(), Error> {
+    fn remove_uki(&self, kernel: &str, flavor: &str) -> Result<Outcome, AppError> {
         let uki_path = self.config.output_path(kernel, flavor)?;
 
         if uki_path.is_file() {
+            if self.dry_run {
+                println!("{}.{}", kernel, flavor);
+                println!("  remove: {}", uki_path.to_string_lossy());
+                return Ok(Outcome::Done("planned"));
+            }
+
             log::info!("Removing uki for {}.{}", kernel, flavor);
-            std::fs::remove_file(uki_path)?;
+            std::fs::remove_file(&uki_path).map_err(|e| AppError::IoError {
+                path: uki_path.clone(),
+                source: e,
+            })?;
+            self.update_manifest(|manifest| {
+                manifest.forget(&uki_path);
+                Ok(())
+            });
+
+            return Ok(Outcome::Done("removed"));
         }
 
-        Ok(())
+        Ok(Outcome::Skipped("nothing to remove"))
     }
 
-    fn generate_uki(&self, kernel: &str, flavor: &str) -> Result<(), Error> {
-        let objcopy_path = which::which_in("objcopy", Some("/usr/bin/"), std::env::current_dir()?)?;
-
-        let mut cmd_args = vec![
-            // os-release section
-            "--add-section".into(),
-            format!(
-                ".osrel=\"{}\"",
-                self.config
-                    .os_release_path(kernel, flavor)?
-                    .to_string_lossy()
-            ),
-            "--change-section-vma".into(),
-            ".osrel=0x20000".into(),
-        ];
-
-        if let Some(cmdline) = self.config.cmdline_path(kernel, flavor)? {
-            cmd_args.push("--add-section".into());
-            cmd_args.push(format!(".cmdline=\"{}\"", cmdline.to_string_lossy()));
-            cmd_args.push("--change-section-vma".into());
-            cmd_args.push(".cmdline=0x30000".into());
-        }
+    fn generate_uki(&self, kernel: &str, flavor: &str) -> Result<Outcome, AppError> {
+        let objcopy_path =
+            which::which_in("objcopy", Some("/usr/bin/"), "/").map_err(|e| AppError::Tool {
+                tool: "objcopy".into(),
+                reason: e.to_string(),
+            })?;
+        let plan = Plan::from_config(&self.config, kernel, flavor)?;
+        let inputs = inputs(&plan)?;
+
+        // Hashing the output can take a while, don't block the other jobs meanwhile
+        let record = self.manifest.lock().unwrap().get(&plan.output).cloned();
+        let fresh = record.is_some_and(|record| record.is_fresh(&plan.output, &inputs));
+        if fresh && !self.force {
+            log::info!("Skipping {}.{}, its inputs didn't change", kernel, flavor);
+            if self.dry_run {
+                println!("{}.{}", kernel, flavor);
+                println!("  up to date, nothing to do (use --force to rebuild)");
+            }
 
-        if let Some(splash_image) = self.config.splash_image_path(kernel, flavor)? {
-            cmd_args.push("--add-section".into());
-            cmd_args.push(format!(".splash=\"{}\"", splash_image.to_string_lossy()));
-            cmd_args.push("--change-section-vma".into());
-            cmd_args.push(".splash=0x40000".into());
+            return Ok(Outcome::Skipped("up to date"));
         }
 
-        cmd_args.extend_from_slice(&[
-            // Linux image section
-            "--add-section".into(),
-            format!(
-                ".linux=\"{}\"",
-                self.config.linux_path(kernel, flavor)?.to_string_lossy()
-            ),
-            "--change-section-vma".into(),
-            ".linux=0x2000000".into(),
-            // Initrd image section
-            "--add-section".into(),
-            format!(
-                ".initrd=\"{}\"",
-                self.config.initrd_path(kernel, flavor)?.to_string_lossy()
-            ),
-            "--change-section-vma".into(),
-            ".initrd=0x3000000".into(),
-            // EFI stub file
-            format!(
-                "\"{}\"",
-                self.config.efistub_path(kernel, flavor)?.to_string_lossy()
-            ),
-            // Output file
-            format!(
-                "\"{}\"",
-                self.config.output_path(kernel, flavor)?.to_string_lossy()
-            ),
-        ]);
+        if self.dry_run {
+            plan.print(&objcopy_path)?;
+            return Ok(Outcome::Done("planned"));
+        }
 
         log::info!("Generating unified kernel image for {}.{}", kernel, flavor);
-        let parent: PathBuf = self
-            .config
-            .output_path(kernel, flavor)?
-            .parent()
-            .map_or(std::env::current_dir()?, |p| p.into());
-
-        log::debug!("Arguments for objcopy: {:#?}", &cmd_args);
+        if let Some(parent) = plan.output.parent().filter(|p| !p.as_os_str().is_empty()) {
+            maybe_create_dir(parent).map_err(|e| AppError::IoError {
+                path: parent.into(),
+                source: e,
+            })?;
+        }
 
-        maybe_create_dir(parent)?;
-        shells::wrap_sh!("{} {}", objcopy_path.to_string_lossy(), cmd_args.join(" "))?;
+        plan.build(&objcopy_path)?;
         log::info!("Successfully generated!");
-        Ok(())
+
+        let record = Record::new(format!("{}.{}", kernel, flavor), &plan.output, inputs);
+        self.update_manifest(|manifest| record.map(|record| manifest.insert(&plan.output, record)));
+        Ok(Outcome::Done("built"))
+    }
+
+    fn update_manifest(&self, update: impl FnOnce(&mut Manifest) -> Result<(), AppError>) {
+        let mut manifest = self.manifest.lock().unwrap();
+
+        // Not being able to update the manifest only means a rebuild next time
+        if let Err(e) = update(&mut manifest).and_then(|_| manifest.save()) {
+            log::warn!("Couldn't update manifest: {}", e);
+        }
     }
 }
 
+fn print_summary(results: &Results) {
+    let width = results
+        .iter()
+        .map(|(entry, _)| entry.len())
+        .max()
+        .unwrap_or(0);
+    let (mut done, mut skipped, mut failed) = (0, 0, 0);
+
+    println!("Summary:");
+    for (entry, result) in results {
+        let (status, detail) = match result {
+            Ok(Outcome::Done(status)) => {
+                done += 1;
+                (*status, String::new())
+            }
+            Ok(Outcome::Skipped(reason)) => {
+                skipped += 1;
+                ("skipped", reason.to_string())
+            }
+            Err(e) => {
+                failed += 1;
+                let detail = match e {
+                    AppError::Entry { key: Some(key), .. } => format!("{}: {}", key, e.detail()),
+                    _ => e.detail().to_string(),
+                };
+
+                // Tools can fail with whole screens of output, the full
+                // error was already logged as it happened
+                let detail = detail.lines().next().unwrap_or_default().to_string();
+                ("failed", detail)
+            }
+        };
+
+        let line = format!(
+            "  {:<width$}  {:<8} {}",
+            entry,
+            status,
+            detail,
+            width = width
+        );
+        println!("{}", line.trim_end());
+    }
+
+    println!("{} succeeded, {} skipped, {} failed", done, skipped, failed);
+}
+
 fn maybe_create_dir(path: impl AsRef<Path>) -> std::io::Result<()> {
     match std::fs::create_dir_all(path) {
         Err(e) => match e.kind() {
@@ -182,3 +451,19 @@ fn maybe_create_dir(path: impl AsRef<Path>) -> std::io::Result<()> {
         Ok(_) => Ok(()),
     }
 }
+
+fn format_age(age: Duration) -> String {
+    let secs = age.as_secs();
+    let (value, unit) = match secs {
+        0..=59 => (secs, "second"),
+        60..=3599 => (secs / 60, "minute"),
+        3600..=86399 => (secs / 3600, "hour"),
+        _ => (secs / 86400, "day"),
+    };
+
+    if value == 1 {
+        format!("{} {}", value, unit)
+    } else {
+        format!("{} {}s", value, unit)
+    }
+}
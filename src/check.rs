@@ -0,0 +1,426 @@
+// Copyright (C) 2020 Kevin Dc
+//
+// This file is part of genuki.
+//
+// genuki is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// genuki is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with genuki.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Error};
+use clap::ArgMatches;
+use yaml_rust::parser::{Event, MarkedEventReceiver, Parser};
+use yaml_rust::scanner::Marker;
+
+use crate::config::{
+    Config, CMDLINE_KEYS, FLAVOR_KEYS, KERNEL_KEYS, OPTION_KEYS, POLICY_KEYS, TEMPLATE_KEYS,
+    VERITY_KEYS,
+};
+use crate::error::AppError;
+
+/// Minimal YAML tree that remembers where each mapping key was found
+#[derive(Debug)]
+enum Node {
+    Map(Vec<(String, Marker, Node)>),
+    Seq,
+    Scalar(String),
+}
+
+#[derive(Debug)]
+enum Frame {
+    Map(Vec<(String, Marker, Node)>, Option<(String, Marker)>),
+    Seq,
+}
+
+#[derive(Debug, Default)]
+struct Loader {
+    stack: Vec<Frame>,
+    root: Option<Node>,
+}
+
+impl Loader {
+    fn insert(&mut self, node: Node, mark: Marker) {
+        match self.stack.last_mut() {
+            None => self.root = Some(node),
+            Some(Frame::Seq) => {}
+            Some(Frame::Map(entries, pending)) => match pending.take() {
+                Some((key, key_mark)) => entries.push((key, key_mark, node)),
+                None => {
+                    let key = match node {
+                        Node::Scalar(key) => key,
+                        _ => "<complex key>".into(),
+                    };
+
+                    *pending = Some((key, mark));
+                }
+            },
+        }
+    }
+}
+
+impl MarkedEventReceiver for Loader {
+    fn on_event(&mut self, event: Event, mark: Marker) {
+        match event {
+            Event::MappingStart(_) => self.stack.push(Frame::Map(Vec::new(), None)),
+            Event::SequenceStart(_) => self.stack.push(Frame::Seq),
+            Event::Scalar(value, ..) => self.insert(Node::Scalar(value), mark),
+            Event::Alias(_) => self.insert(Node::Scalar(String::new()), mark),
+            Event::MappingEnd | Event::SequenceEnd => {
+                let node = match self.stack.pop() {
+                    Some(Frame::Map(entries, _)) => Node::Map(entries),
+                    Some(Frame::Seq) => Node::Seq,
+                    None => return,
+                };
+
+                self.insert(node, mark);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// A single problem found in the configuration
+#[derive(Debug)]
+struct Diagnostic {
+    path: PathBuf,
+    location: Option<(usize, usize)>,
+    message: String,
+}
+
+#[derive(Debug)]
+struct Checker {
+    /// File being checked
+    path: PathBuf,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Checker {
+    fn report(&mut self, mark: Option<Marker>, message: String) {
+        self.diagnostics.push(Diagnostic {
+            path: self.path.clone(),
+            // yaml-rust lines are 1-based but columns are 0-based
+            location: mark.map(|m| (m.line(), m.col() + 1)),
+            message,
+        });
+    }
+
+    fn check_keys(&mut self, context: &str, node: &Node, mark: Marker, allowed: &[&str]) {
+        let entries = match node {
+            Node::Map(entries) => entries,
+            _ => {
+                self.report(Some(mark), format!("{} should be a mapping", context));
+                return;
+            }
+        };
+
+        for (key, mark, _) in entries {
+            if allowed.contains(&key.as_str()) {
+                continue;
+            }
+
+            let message = match suggest(key, allowed) {
+                Some(suggestion) => format!(
+                    "unknown key \"{}\" in {} (did you mean \"{}\"?)",
+                    key, context, suggestion
+                ),
+                None => format!("unknown key \"{}\" in {}", key, context),
+            };
+
+            self.report(Some(*mark), message);
+        }
+    }
+
+    // Options that are mappings themselves
+    fn check_option(&mut self, context: &str, key: &str, node: &Node, mark: Marker) {
+        let context = format!("{}.{}", context, key);
+        match key {
+            "cmdline" if matches!(node, Node::Map(_)) => {
+                self.check_keys(&context, node, mark, CMDLINE_KEYS)
+            }
+            "cmdline-policy" => self.check_keys(&context, node, mark, POLICY_KEYS),
+            "verity" => self.check_keys(&context, node, mark, VERITY_KEYS),
+            _ => {}
+        }
+    }
+
+    fn check_tree(&mut self, root: &Node) {
+        let kernels = match root {
+            Node::Map(kernels) => kernels,
+            Node::Scalar(s) if s.is_empty() || s == "~" => return,
+            _ => {
+                self.report(None, "top level should be a mapping of kernels".into());
+                return;
+            }
+        };
+
+        for (name, mark, node) in kernels {
+            match name.as_str() {
+                "defaults" => self.check_kernel(name, node, *mark, &[OPTION_KEYS]),
+                "templates" => self.check_templates(node, *mark),
+                _ => self.check_kernel(name, node, *mark, &[OPTION_KEYS, KERNEL_KEYS]),
+            }
+        }
+    }
+
+    fn check_templates(&mut self, node: &Node, mark: Marker) {
+        let templates = match node {
+            Node::Map(templates) => templates,
+            _ => {
+                self.report(Some(mark), "templates should be a mapping".into());
+                return;
+            }
+        };
+
+        for (name, mark, node) in templates {
+            let context = format!("templates.{}", name);
+            let allowed = [OPTION_KEYS, KERNEL_KEYS, TEMPLATE_KEYS];
+            self.check_kernel(&context, node, *mark, &allowed);
+        }
+    }
+
+    // Defaults, kernels and templates share most of their keys
+    fn check_kernel(&mut self, context: &str, node: &Node, mark: Marker, allowed: &[&[&str]]) {
+        self.check_keys(context, node, mark, &allowed.concat());
+
+        let entries = match node {
+            Node::Map(entries) => entries,
+            _ => return,
+        };
+
+        let has_flavors = allowed.contains(&KERNEL_KEYS);
+        for (key, mark, value) in entries {
+            match key.as_str() {
+                "flavors" if has_flavors => self.check_flavors(context, value, *mark),
+                key => self.check_option(context, key, value, *mark),
+            }
+        }
+    }
+
+    fn check_flavors(&mut self, kernel: &str, node: &Node, mark: Marker) {
+        let flavors = match node {
+            Node::Map(flavors) => flavors,
+            _ => {
+                self.report(
+                    Some(mark),
+                    format!("{}.flavors should be a mapping", kernel),
+                );
+                return;
+            }
+        };
+
+        for (flavor, mark, node) in flavors {
+            let context = format!("{}.{}", kernel, flavor);
+            self.check_keys(&context, node, *mark, &[OPTION_KEYS, FLAVOR_KEYS].concat());
+
+            if let Node::Map(entries) = node {
+                for (key, mark, value) in entries {
+                    self.check_option(&context, key, value, *mark);
+                }
+            }
+        }
+    }
+
+    fn check_entries(&mut self, config: &Config) {
+        let mut outputs: HashMap<PathBuf, Vec<String>> = HashMap::new();
+
+        let mut entries: Vec<_> = config
+            .kernels
+            .iter()
+            .flat_map(|(kernel, k)| k.flavors.keys().map(move |flavor| (kernel, flavor)))
+            .collect();
+        entries.sort();
+
+        for (kernel, flavor) in entries {
+            let name = format!("{}.{}", kernel, flavor);
+
+            // Resolve every field on its own, so all the missing files are
+            // reported at once instead of only the first one
+            let results = vec![
+                ("os-release", config.os_release_source(kernel, flavor).err()),
+                ("cmdline", config.cmdline_source(kernel, flavor).err()),
+                (
+                    "splash-image",
+                    config.splash_image_path(kernel, flavor).err(),
+                ),
+                ("linux", config.linux_path(kernel, flavor).err()),
+                ("initrd", config.initrd_source(kernel, flavor).err()),
+                ("efistub", config.efistub_path(kernel, flavor).err()),
+            ];
+
+            for (key, error) in results {
+                if let Some(e) = error {
+                    self.report(None, e.context(name.clone(), Some(key)).to_string());
+                }
+            }
+
+            match config.output_path(kernel, flavor) {
+                Ok(output) => outputs.entry(output).or_default().push(name),
+                Err(e) => self.report(None, e.context(name, Some("output")).to_string()),
+            }
+        }
+
+        let mut duplicated: Vec<_> = outputs.into_iter().filter(|(_, v)| v.len() > 1).collect();
+        duplicated.sort();
+
+        for (output, names) in duplicated {
+            self.report(
+                None,
+                format!(
+                    "{} write the same output \"{}\"",
+                    names.join(", "),
+                    output.to_string_lossy()
+                ),
+            );
+        }
+    }
+
+    fn print(&self) {
+        for diagnostic in &self.diagnostics {
+            match diagnostic.location {
+                Some((line, col)) => println!(
+                    "{}:{}:{}: {}",
+                    diagnostic.path.to_string_lossy(),
+                    line,
+                    col,
+                    diagnostic.message
+                ),
+                None => println!(
+                    "{}: {}",
+                    diagnostic.path.to_string_lossy(),
+                    diagnostic.message
+                ),
+            }
+        }
+    }
+}
+
+// Levenshtein distance between two strings
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<_> = b.chars().collect();
+    let mut row: Vec<_> = (0..=b.len()).collect();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+
+        for (j, cb) in b.iter().enumerate() {
+            let current = row[j + 1];
+            row[j + 1] = if ca == *cb {
+                prev
+            } else {
+                1 + prev.min(row[j]).min(current)
+            };
+            prev = current;
+        }
+    }
+
+    row[b.len()]
+}
+
+fn suggest<'a>(key: &str, candidates: &[&'a str]) -> Option<&'a str> {
+    candidates
+        .iter()
+        .map(|c| (distance(key, c), *c))
+        .filter(|(d, c)| *d <= 2.max(c.len() / 3))
+        .min()
+        .map(|(_, c)| c)
+}
+
+pub fn run(matches: &ArgMatches) -> Result<(), Error> {
+    let path = Path::new(matches.value_of("config").unwrap());
+    let mut checker = Checker {
+        path: path.into(),
+        diagnostics: Vec::new(),
+    };
+
+    // The main config and each of its drop-ins are checked on their own
+    for file in Config::files(path).map_err(AppError::from_config)? {
+        checker.path = file;
+
+        // Not a problem of the config itself, keep the class of the error
+        // (e.g. missing input) for the exit code
+        let contents = match std::fs::read_to_string(&checker.path) {
+            Ok(contents) => contents,
+            Err(e) => {
+                checker.print();
+                return Err(AppError::IoError {
+                    path: checker.path,
+                    source: e,
+                }
+                .into());
+            }
+        };
+
+        let mut loader = Loader::default();
+        match Parser::new(contents.chars()).load(&mut loader, false) {
+            Ok(_) => {
+                if let Some(root) = &loader.root {
+                    checker.check_tree(root);
+                }
+            }
+            Err(e) => {
+                let mark = *e.marker();
+                checker.report(Some(mark), format!("invalid yaml: {}", e));
+            }
+        }
+    }
+
+    checker.path = path.into();
+
+    // Only try to resolve the entries if the file itself looks right,
+    // otherwise we would report the same problem twice
+    if checker.diagnostics.is_empty() {
+        match Config::from_path(path) {
+            Ok(config) => checker.check_entries(&config),
+            Err(e) => checker.report(None, e.to_string()),
+        }
+    }
+
+    checker.print();
+
+    match checker.diagnostics.len() {
+        0 => {
+            log::info!("No problems found in {}", path.to_string_lossy());
+            Ok(())
+        }
+        n => Err(AppError::Config(anyhow!("Found {} problem(s) in the configuration", n)).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn edit_distance() {
+        assert_eq!(distance("", ""), 0);
+        assert_eq!(distance("cmdline", "cmdline"), 0);
+        assert_eq!(distance("", "linux"), 5);
+        assert_eq!(distance("initrd", "intrd"), 1);
+        assert_eq!(distance("efistbu", "efistub"), 2);
+        assert_eq!(distance("kitten", "sitting"), 3);
+    }
+
+    #[test]
+    fn suggestions() {
+        assert_eq!(suggest("intrd", OPTION_KEYS), Some("initrd"));
+        assert_eq!(suggest("os_release", OPTION_KEYS), Some("os-release"));
+        assert_eq!(suggest("extend", FLAVOR_KEYS), Some("extends"));
+        assert_eq!(suggest("whatever", OPTION_KEYS), None);
+
+        // Short keys only get close matches
+        assert_eq!(suggest("ab", &["cd", "abc"]), Some("abc"));
+        assert_eq!(suggest("xyz", &["abc"]), None);
+    }
+}
@@ -0,0 +1,396 @@
+// Copyright (C) 2020 Kevin Dc
+//
+// This file is part of genuki.
+//
+// genuki is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// genuki is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with genuki.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::fmt;
+
+use serde::Deserialize;
+use thiserror::Error;
+
+/// Longest cmdline accepted by default (COMMAND_LINE_SIZE on x86)
+const DEFAULT_MAX_LENGTH: usize = 2048;
+
+/// Params the kernel (or systemd) only takes once, the rest can be repeated
+/// (e.g. console=, video=, systemd.setenv=)
+const SINGLE_VALUE: &[&str] = &[
+    "init",
+    "lockdown",
+    "mount.usr",
+    "mount.usrflags",
+    "mount.usrfstype",
+    "rdinit",
+    "resume",
+    "resume_offset",
+    "root",
+    "rootflags",
+    "rootfstype",
+    "roothash",
+    "systemd.unit",
+    "usrhash",
+];
+
+/// Params that make no sense together
+const CONFLICTS: &[(&str, &str)] = &[("ro", "rw")];
+
+/// Prefixes of the values 'root=' accepts
+const ROOT_PREFIXES: &[&str] = &[
+    "/dev/",
+    "UUID=",
+    "PARTUUID=",
+    "LABEL=",
+    "PARTLABEL=",
+    "ZFS=",
+    "zfs:",
+    "nfs:",
+    "nfs4:",
+    "cifs:",
+    "live:",
+    "block:",
+    "iscsi:",
+    "nbd:",
+];
+
+/// Values 'root=' accepts as is
+const ROOT_VALUES: &[&str] = &["gpt-auto", "tmpfs", "fstab", "zfs"];
+
+/// Rules a cmdline must follow before being embedded
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Policy {
+    /// Params not allowed, by name (e.g. "init") or exactly (e.g. "lockdown=none")
+    #[serde(default)]
+    deny: Vec<String>,
+    /// Params that take a single value but can be given more than once anyway
+    #[serde(default)]
+    repeatable: Vec<String>,
+    #[serde(rename = "max-length")]
+    max_length: Option<usize>,
+}
+
+#[derive(Debug, Error)]
+pub enum CmdlineError {
+    #[error("'{0}' is given more than once")]
+    Duplicated(String),
+
+    #[error("'{0}' conflicts with '{1}'")]
+    Conflict(String, String),
+
+    #[error("'{0}' isn't a known root= syntax")]
+    InvalidRoot(String),
+
+    #[error("'{0}' goes past the limit of {1} bytes")]
+    TooLong(String, usize),
+
+    #[error("'{0}' is denied by the cmdline policy")]
+    Denied(String),
+}
+
+/// Kernel command line as a list of params, e.g. ["root=UUID=...", "rw"]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Cmdline {
+    params: Vec<String>,
+}
+
+/// Name of a param, i.e. everything before the first '='
+pub fn param_name(param: &str) -> &str {
+    param.split('=').next().unwrap_or(param)
+}
+
+// Whether a param matches a name (e.g. "quiet") or a whole param
+fn matches(param: &str, pattern: &str) -> bool {
+    if pattern.contains('=') {
+        param == pattern
+    } else {
+        param_name(param) == pattern
+    }
+}
+
+fn valid_root(value: &str) -> bool {
+    let is_devno = value
+        .split(':')
+        .all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_hexdigit()));
+
+    is_devno
+        || ROOT_VALUES.contains(&value)
+        || ROOT_PREFIXES
+            .iter()
+            .any(|prefix| value.len() > prefix.len() && value.starts_with(prefix))
+}
+
+// Split on whitespace, but not inside double quotes (e.g. foo="a b")
+fn split(line: &str) -> Vec<String> {
+    let mut params = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+
+    for c in line.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                current.push(c);
+            }
+            c if c.is_whitespace() && !quoted => {
+                if !current.is_empty() {
+                    params.push(std::mem::take(&mut current));
+                }
+            }
+            c => current.push(c),
+        }
+    }
+
+    if !current.is_empty() {
+        params.push(current);
+    }
+
+    params
+}
+
+impl Cmdline {
+    /// Add the params of some contents, which can span multiple lines and
+    /// have comments (lines starting with '#')
+    pub fn extend(&mut self, contents: &str) {
+        let mut params = Vec::new();
+        for line in contents.lines() {
+            if !line.trim_start().starts_with('#') {
+                params.extend(split(line));
+            }
+        }
+
+        // Everything after '--' goes to init, keep new kernel params before it
+        let separator = match self.params.iter().position(|p| p == "--") {
+            Some(separator) => separator,
+            None => return self.params.extend(params),
+        };
+
+        let init_params = match params.iter().position(|p| p == "--") {
+            Some(index) => params.split_off(index + 1),
+            None => Vec::new(),
+        };
+
+        params.retain(|p| p != "--");
+        self.params.splice(separator..separator, params);
+        self.params.extend(init_params);
+    }
+
+    /// Remove a param, every one with that name (e.g. "quiet", "console") or
+    /// only the exact matches if it has a value (e.g. "console=ttyS0")
+    pub fn remove(&mut self, param: &str) {
+        self.params.retain(|p| !matches(p, param));
+    }
+
+    /// Check the kernel params (those before '--') against a policy
+    pub fn lint(&self, policy: &Policy) -> Result<(), CmdlineError> {
+        let params = match self.params.iter().position(|p| p == "--") {
+            Some(separator) => &self.params[..separator],
+            None => &self.params[..],
+        };
+
+        for (i, param) in params.iter().enumerate() {
+            let name = param_name(param);
+            let single =
+                SINGLE_VALUE.contains(&name) && !policy.repeatable.iter().any(|r| r == name);
+            for previous in &params[..i] {
+                if single && previous == param {
+                    return Err(CmdlineError::Duplicated(param.clone()));
+                }
+
+                if single && param_name(previous) == name {
+                    return Err(CmdlineError::Conflict(param.clone(), previous.clone()));
+                }
+
+                let conflicts = CONFLICTS.iter().any(|(a, b)| {
+                    (name == *a && param_name(previous) == *b)
+                        || (name == *b && param_name(previous) == *a)
+                });
+
+                if conflicts {
+                    return Err(CmdlineError::Conflict(param.clone(), previous.clone()));
+                }
+            }
+
+            if let Some(root) = param.strip_prefix("root=") {
+                if !valid_root(root) {
+                    return Err(CmdlineError::InvalidRoot(param.clone()));
+                }
+            }
+
+            if policy.deny.iter().any(|denied| matches(param, denied)) {
+                return Err(CmdlineError::Denied(param.clone()));
+            }
+        }
+
+        let max_length = policy.max_length.unwrap_or(DEFAULT_MAX_LENGTH);
+        let mut length = 0;
+        for param in &self.params {
+            length += param.len() + if length == 0 { 0 } else { 1 };
+            if length > max_length {
+                return Err(CmdlineError::TooLong(param.clone(), max_length));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for Cmdline {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.params.join(" "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(contents: &str) -> Cmdline {
+        let mut cmdline = Cmdline::default();
+        cmdline.extend(contents);
+        cmdline
+    }
+
+    #[test]
+    fn extend_skips_comments_and_keeps_quotes() {
+        let cmdline = parse("# root\nroot=/dev/sda1 rw\n  # quiet\nfoo=\"a b\"  quiet\n");
+        assert_eq!(cmdline.to_string(), "root=/dev/sda1 rw foo=\"a b\" quiet");
+    }
+
+    #[test]
+    fn extend_appends() {
+        let mut cmdline = parse("root=/dev/sda1 rw");
+        cmdline.extend("quiet splash");
+        assert_eq!(cmdline.to_string(), "root=/dev/sda1 rw quiet splash");
+    }
+
+    #[test]
+    fn extend_keeps_init_params_last() {
+        let mut cmdline = parse("rw -- single");
+        cmdline.extend("quiet");
+        assert_eq!(cmdline.to_string(), "rw quiet -- single");
+
+        cmdline.extend("splash -- emergency");
+        assert_eq!(cmdline.to_string(), "rw quiet splash -- single emergency");
+
+        let mut cmdline = parse("rw");
+        cmdline.extend("quiet -- single");
+        assert_eq!(cmdline.to_string(), "rw quiet -- single");
+    }
+
+    #[test]
+    fn remove_by_name_or_exactly() {
+        let mut cmdline = parse("console=tty0 quiet console=ttyS0 quiet=1 splash");
+        cmdline.remove("console=ttyS0");
+        assert_eq!(cmdline.to_string(), "console=tty0 quiet quiet=1 splash");
+
+        cmdline.remove("quiet");
+        assert_eq!(cmdline.to_string(), "console=tty0 splash");
+
+        cmdline.remove("console");
+        cmdline.remove("missing");
+        assert_eq!(cmdline.to_string(), "splash");
+    }
+
+    #[test]
+    fn lint_allows_repeated_params() {
+        let policy = Policy::default();
+        for contents in &[
+            "video=HDMI-A-1:1920x1080 video=DP-1:2560x1440",
+            "systemd.setenv=A=1 systemd.setenv=B=2",
+            "acpi_osi=! acpi_osi=Linux",
+            "memmap=64K$0x1000 memmap=1M$0x2000",
+            "hugepagesz=1G hugepages=4 hugepagesz=2M hugepages=512",
+            "earlycon earlycon=uart,io,0x3f8",
+            "console=tty0 console=ttyS0,115200",
+            "quiet quiet",
+        ] {
+            assert!(parse(contents).lint(&policy).is_ok(), "{}", contents);
+        }
+    }
+
+    #[test]
+    fn lint_rejects_single_value_params_given_twice() {
+        let policy = Policy::default();
+        assert!(matches!(
+            parse("root=/dev/sda1 root=/dev/sda1").lint(&policy),
+            Err(CmdlineError::Duplicated(_))
+        ));
+        assert!(matches!(
+            parse("root=/dev/sda1 rw root=/dev/sda2").lint(&policy),
+            Err(CmdlineError::Conflict(_, _))
+        ));
+        assert!(matches!(
+            parse("ro quiet rw").lint(&policy),
+            Err(CmdlineError::Conflict(_, _))
+        ));
+    }
+
+    #[test]
+    fn lint_policy_repeatable() {
+        let policy = Policy {
+            repeatable: vec!["resume".into()],
+            ..Policy::default()
+        };
+        assert!(parse("resume=/dev/sda2 resume=/dev/sda3")
+            .lint(&policy)
+            .is_ok());
+    }
+
+    #[test]
+    fn lint_root_syntax() {
+        let policy = Policy::default();
+        for root in &["/dev/sda1", "UUID=1234", "PARTUUID=ab", "gpt-auto", "8:1"] {
+            let contents = format!("root={}", root);
+            assert!(parse(&contents).lint(&policy).is_ok(), "{}", root);
+        }
+
+        for root in &["sda1", "UUID=", "/dev/"] {
+            let contents = format!("root={}", root);
+            assert!(matches!(
+                parse(&contents).lint(&policy),
+                Err(CmdlineError::InvalidRoot(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn lint_deny() {
+        let policy = Policy {
+            deny: vec!["init".into(), "lockdown=none".into()],
+            ..Policy::default()
+        };
+        assert!(matches!(
+            parse("init=/bin/sh").lint(&policy),
+            Err(CmdlineError::Denied(_))
+        ));
+        assert!(matches!(
+            parse("lockdown=none").lint(&policy),
+            Err(CmdlineError::Denied(_))
+        ));
+        assert!(parse("lockdown=integrity").lint(&policy).is_ok());
+        assert!(parse("quiet -- init=/bin/sh").lint(&policy).is_ok());
+    }
+
+    #[test]
+    fn lint_length() {
+        let policy = Policy {
+            max_length: Some(16),
+            ..Policy::default()
+        };
+        assert!(parse("quiet splash").lint(&policy).is_ok());
+        assert!(matches!(
+            parse("quiet splash loglevel=3").lint(&policy),
+            Err(CmdlineError::TooLong(_, 16))
+        ));
+    }
+}
@@ -15,18 +15,24 @@
 // You should have received a copy of the GNU General Public License
 // along with genuki.  If not, see <http://www.gnu.org/licenses/>.
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs::File;
-use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::io::{ErrorKind, Read};
 use std::path::{Component, Path, PathBuf};
 
-use anyhow::Error;
+use anyhow::{anyhow, Error};
 use clap::ArgMatches;
+use regex::Regex;
 use serde::Deserialize;
+use yaml_rust::{Yaml, YamlLoader};
 
+use crate::cmdline::{param_name, Cmdline, Policy};
 use crate::error::AppError;
-use crate::format::FormatPath;
-use crate::temp;
+use crate::format::{self, FormatError, FormatPath, Vars};
+use crate::kernels::{self, InstalledKernel};
+use crate::os_release::OsRelease;
+use crate::system;
+use crate::uki::Source;
 
 #[derive(Debug, Clone, Deserialize)]
 #[serde(untagged)]
@@ -35,6 +41,32 @@ pub enum InlineOrPath {
     Inline { inline: String },
 }
 
+/// A cmdline given as a file, a list of fragments or a mapping
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum CmdlineOption {
+    Path(FormatPath),
+    Fragments(Vec<InlineOrPath>),
+    Full {
+        inline: Option<String>,
+        #[serde(default)]
+        fragments: Vec<InlineOrPath>,
+        /// Add to the cmdline of the parent flavor, kernel or defaults
+        #[serde(default)]
+        append: bool,
+        /// Params removed from the inherited cmdline (and drop-ins)
+        #[serde(default)]
+        remove: Vec<String>,
+    },
+}
+
+/// Files with the dm-verity root hash of the root and /usr partitions
+#[derive(Debug, Clone, Deserialize)]
+pub struct Verity {
+    roothash: Option<FormatPath>,
+    usrhash: Option<FormatPath>,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 #[serde(untagged)]
 pub enum OneOrMany<T> {
@@ -42,43 +74,139 @@ pub enum OneOrMany<T> {
     Many(Vec<T>),
 }
 
-#[derive(Debug, Clone, Deserialize)]
-pub struct Flavor {
-    enabled: Option<bool>,
+/// Keys shared by defaults, kernels and flavors, must be kept in sync with `Options`
+pub const OPTION_KEYS: &[&str] = &[
+    "os-release",
+    "os-release-fields",
+    "id-suffix",
+    "title",
+    "cmdline",
+    "splash-image",
+    "linux",
+    "initrd",
+    "efistub",
+    "output",
+    "proc-cmdline",
+    "cmdline-policy",
+    "crypttab",
+    "verity",
+];
+
+/// Keys only accepted inside a flavor, must be kept in sync with `Flavor`
+pub const FLAVOR_KEYS: &[&str] = &["enabled", "extends"];
+
+/// Keys only accepted inside a kernel, must be kept in sync with `Kernel`
+pub const KERNEL_KEYS: &[&str] = &["flavors"];
+
+/// Keys of a cmdline given as a mapping, must be kept in sync with `CmdlineOption`
+pub const CMDLINE_KEYS: &[&str] = &["inline", "fragments", "append", "remove"];
+
+/// Keys of a cmdline policy, must be kept in sync with `cmdline::Policy`
+pub const POLICY_KEYS: &[&str] = &["deny", "repeatable", "max-length"];
+
+/// Keys of the verity option, must be kept in sync with `Verity`
+pub const VERITY_KEYS: &[&str] = &["roothash", "usrhash"];
+
+/// Keys only accepted inside a template, must be kept in sync with `Template`
+pub const TEMPLATE_KEYS: &[&str] = &["match", "regex", "exclude"];
+
+/// Params that can be set globally (in 'defaults'), for a kernel or for a
+/// flavor. The most specific one wins: flavor, then kernel, then defaults.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Options {
     #[serde(rename = "os-release")]
-    os_release: Option<PathBuf>,
+    os_release: Option<FormatPath>,
+    /// Fields of the os-release to replace (or remove, if empty)
+    #[serde(rename = "os-release-fields")]
+    os_release_fields: Option<BTreeMap<String, String>>,
+    /// Whether to add '-<flavor>' to the ID of a rewritten os-release
+    #[serde(rename = "id-suffix")]
+    id_suffix: Option<bool>,
     title: Option<String>,
-    cmdline: Option<InlineOrPath>,
+    cmdline: Option<CmdlineOption>,
     #[serde(rename = "splash-image")]
     splash_image: Option<FormatPath>,
     linux: Option<FormatPath>,
     initrd: Option<OneOrMany<FormatPath>>,
-    efistub: Option<PathBuf>,
-    output: FormatPath,
+    efistub: Option<FormatPath>,
+    output: Option<FormatPath>,
+    /// Use the cmdline of the running kernel as the last fallback
+    #[serde(rename = "proc-cmdline")]
+    proc_cmdline: Option<bool>,
+    #[serde(rename = "cmdline-policy")]
+    cmdline_policy: Option<Policy>,
+    /// Add the params to unlock an encrypted root, from crypttab
+    crypttab: Option<bool>,
+    verity: Option<Verity>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Flavor {
+    /// Not inherited through 'extends'
+    enabled: Option<bool>,
+    /// Flavor of the same kernel to take unset params from
+    extends: Option<String>,
+    #[serde(flatten)]
+    options: Options,
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct Kernel {
-    /// The following params are global optionals, that
-    /// can be overriden with specific flavor params.
-    cmdline: Option<InlineOrPath>,
-    linux: Option<FormatPath>,
-    #[serde(rename = "splash-image")]
-    splash_image: Option<FormatPath>,
-    efistub: Option<PathBuf>,
+    /// Global optionals for every flavor of this kernel
+    #[serde(flatten)]
+    options: Options,
 
     /// Map of flavors
+    #[serde(default)]
     pub flavors: HashMap<String, Flavor>,
+
+    /// Capture groups of the template this kernel was created from
+    #[serde(skip)]
+    captures: HashMap<String, String>,
 }
 
+/// Kernel definition used for every installed kernel whose name matches
 #[derive(Debug, Clone, Deserialize)]
+pub struct Template {
+    /// Glob, each wildcard is a capture group
+    #[serde(rename = "match")]
+    glob: Option<String>,
+    regex: Option<String>,
+    /// Globs of kernels to leave out
+    #[serde(default)]
+    exclude: Vec<String>,
+    #[serde(flatten)]
+    kernel: Kernel,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
 pub struct Config {
     #[serde(skip)]
     location: PathBuf,
+    #[serde(skip)]
+    installed: Vec<InstalledKernel>,
+    /// Machine-wide optionals for every kernel
+    #[serde(default)]
+    defaults: Options,
+    /// Kernels generated from the installed ones
+    #[serde(default)]
+    templates: BTreeMap<String, Template>,
     #[serde(flatten)]
     pub kernels: HashMap<String, Kernel>,
 }
 
+/// Config used when none is given with '-c'
+pub const DEFAULT_CONFIG: &str = "/etc/genuki/config.yaml";
+
+/// Vendor drop-ins, e.g. shipped by kernel packages
+const VENDOR_DROP_IN_DIR: &str = "/usr/share/genuki/conf.d";
+
+/// Cmdline drop-ins, relative to the directory of the main config
+const CMDLINE_DROP_IN_DIR: &str = "cmdline.d";
+
+/// Cmdlines used when none is configured, same as kernel-install
+const FALLBACK_CMDLINES: &[&str] = &["/etc/kernel/cmdline", "/usr/lib/kernel/cmdline"];
+
 // Naive canonicalize a file with respect to another path
 // TODO: Probably resolve symlinks?
 fn canonicalize(relative_to: impl AsRef<Path>, path: impl AsRef<Path>) -> PathBuf {
@@ -165,127 +293,835 @@ fn populate_initrd(kernel: &str, flavor: &str) -> Result<OneOrMany<FormatPath>,
     }
 }
 
+// Either a bare hex hash (e.g. image.roothash) or the output of
+// 'veritysetup format' saved next to the image
+fn read_root_hash(path: &Path) -> Result<String, AppError> {
+    let contents = std::fs::read_to_string(path).map_err(|e| AppError::IoError {
+        path: path.into(),
+        source: e,
+    })?;
+
+    let hash = contents
+        .lines()
+        .find_map(|line| line.strip_prefix("Root hash:"))
+        .unwrap_or(&contents)
+        .trim();
+
+    let is_hex =
+        hash.len() >= 32 && hash.len() % 2 == 0 && hash.chars().all(|c| c.is_ascii_hexdigit());
+    if !is_hex {
+        return Err(AppError::InvalidRootHash(path.into()));
+    }
+
+    Ok(hash.to_lowercase())
+}
+
+// First of the candidates that exists, without what the bootloader adds
+fn first_cmdline<'a>(candidates: &[&'a Path]) -> Result<Option<(&'a Path, Cmdline)>, AppError> {
+    for path in candidates {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => {
+                return Err(AppError::IoError {
+                    path: path.into(),
+                    source: e,
+                })
+            }
+        };
+
+        let mut cmdline = Cmdline::default();
+        cmdline.extend(&contents);
+
+        // Added by the bootloader, they don't make sense in an image
+        cmdline.remove("BOOT_IMAGE");
+        cmdline.remove("initrd");
+        return Ok(Some((path, cmdline)));
+    }
+
+    Ok(None)
+}
+
+fn template_error(kernel: &str, flavor: &str, key: &str, source: FormatError) -> AppError {
+    AppError::Template(source).context(format!("{}.{}", kernel, flavor), Some(key))
+}
+
+// Render a format of an entry, errors point to the config key it came from
+fn render(
+    kernel: &str,
+    flavor: &str,
+    key: &str,
+    format: &FormatPath,
+    vars: &Vars,
+) -> Result<PathBuf, AppError> {
+    format
+        .render(vars)
+        .map_err(|e| template_error(kernel, flavor, key, e))
+}
+
+// A '[...]' or '[!...]' class at the start of 'chars', parsed like
+// glob::Pattern does (a ']' right after the bracket is taken literally), as
+// a regex group and the number of chars it takes
+fn glob_class(chars: &[char]) -> Option<(String, usize)> {
+    let (negated, start) = match chars.get(1) {
+        Some('!') => (true, 2),
+        _ => (false, 1),
+    };
+
+    let end = start + 1 + chars.get(start + 1..)?.iter().position(|c| *c == ']')?;
+    let specifiers = &chars[start..end];
+    let escape = |c: char| regex::escape(&c.to_string());
+
+    let mut class = String::new();
+    let mut i = 0;
+    while i < specifiers.len() {
+        if i + 3 <= specifiers.len() && specifiers[i + 1] == '-' {
+            // Reversed ranges never match in glob, and are invalid in a regex
+            let (low, high) = (specifiers[i], specifiers[i + 2]);
+            if low <= high {
+                class.push_str(&format!("{}-{}", escape(low), escape(high)));
+            }
+            i += 3;
+        } else {
+            class.push_str(&escape(specifiers[i]));
+            i += 1;
+        }
+    }
+
+    let group = match (negated, class.is_empty()) {
+        (true, true) => "(.)".into(),
+        // Never matches, empty classes aren't allowed in a regex
+        (false, true) => r"(\b\B.)".into(),
+        (true, false) => format!("([^{}])", class),
+        (false, false) => format!("([{}])", class),
+    };
+
+    Some((group, end + 1))
+}
+
+// Translate a glob into an anchored regex with the same syntax as
+// glob::Pattern, with a capture group per wildcard ('*', '?' and classes)
+fn glob_to_regex(glob: &str) -> String {
+    let chars: Vec<_> = glob.chars().collect();
+    let mut regex = String::from("^");
+    let mut i = 0;
+
+    while i < chars.len() {
+        match chars[i] {
+            '*' => {
+                while chars.get(i + 1) == Some(&'*') {
+                    i += 1;
+                }
+                regex.push_str("(.*)");
+            }
+            '?' => regex.push_str("(.)"),
+            '[' => match glob_class(&chars[i..]) {
+                Some((class, len)) => {
+                    regex.push_str(&class);
+                    i += len;
+                    continue;
+                }
+                None => regex.push_str(r"\["),
+            },
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+
+        i += 1;
+    }
+
+    regex.push('$');
+    regex
+}
+
+/// A template ready to be matched against kernel names
+struct Matcher {
+    /// 'match' decides which kernels match, its regex only gets the captures
+    glob: Option<glob::Pattern>,
+    regex: Regex,
+    excludes: Vec<glob::Pattern>,
+}
+
+impl Template {
+    fn from_any(kernel: Kernel) -> Self {
+        Self {
+            glob: Some("*".into()),
+            regex: None,
+            exclude: Vec::new(),
+            kernel,
+        }
+    }
+
+    fn compile(&self, template: &str) -> Result<Matcher, Error> {
+        let invalid =
+            |e: &dyn std::fmt::Display| anyhow!("Invalid pattern in template {}: {}", template, e);
+        let (glob, regex) = match (&self.glob, &self.regex) {
+            (Some(glob), None) => {
+                let pattern = glob::Pattern::new(glob).map_err(|e| invalid(&e))?;
+                (Some(pattern), glob_to_regex(glob))
+            }
+            (None, Some(regex)) => (None, format!("^(?:{})$", regex)),
+            _ => {
+                return Err(anyhow!(
+                    "Template {} must have either 'match' or 'regex'",
+                    template
+                ))
+            }
+        };
+
+        let regex = Regex::new(&regex).map_err(|e| invalid(&e))?;
+
+        let excludes: Result<Vec<_>, _> = self
+            .exclude
+            .iter()
+            .map(|exclude| glob::Pattern::new(exclude))
+            .collect();
+
+        let excludes =
+            excludes.map_err(|e| anyhow!("Invalid exclude in template {}: {}", template, e))?;
+
+        Ok(Matcher {
+            glob,
+            regex,
+            excludes,
+        })
+    }
+
+    /// Kernel definition for 'name', if this template applies to it
+    fn instantiate(&self, matcher: &Matcher, name: &str) -> Option<Kernel> {
+        let Matcher {
+            glob,
+            regex,
+            excludes,
+        } = matcher;
+
+        let mismatch = glob.as_ref().is_some_and(|glob| !glob.matches(name));
+        if mismatch || excludes.iter().any(|pattern| pattern.matches(name)) {
+            return None;
+        }
+
+        let captures = regex.captures(name)?;
+        let mut kernel = self.kernel.clone();
+
+        for (i, capture) in captures.iter().enumerate().skip(1) {
+            let value = capture.map_or("", |c| c.as_str()).to_owned();
+            kernel.captures.insert(i.to_string(), value);
+        }
+
+        for group in regex.capture_names().flatten() {
+            let value = captures.name(group).map_or("", |c| c.as_str()).to_owned();
+            kernel.captures.insert(group.to_owned(), value);
+        }
+
+        Some(kernel)
+    }
+}
+
+impl Options {
+    // Options set in 'other' take precedence
+    fn merge(&mut self, other: Options) {
+        self.os_release = other.os_release.or_else(|| self.os_release.take());
+        self.os_release_fields = match (self.os_release_fields.take(), other.os_release_fields) {
+            (Some(mut fields), Some(other)) => {
+                fields.extend(other);
+                Some(fields)
+            }
+            (fields, other) => other.or(fields),
+        };
+        self.id_suffix = other.id_suffix.or_else(|| self.id_suffix.take());
+        self.title = other.title.or_else(|| self.title.take());
+        self.cmdline = other.cmdline.or_else(|| self.cmdline.take());
+        self.splash_image = other.splash_image.or_else(|| self.splash_image.take());
+        self.linux = other.linux.or_else(|| self.linux.take());
+        self.initrd = other.initrd.or_else(|| self.initrd.take());
+        self.efistub = other.efistub.or_else(|| self.efistub.take());
+        self.output = other.output.or_else(|| self.output.take());
+        self.proc_cmdline = other.proc_cmdline.or_else(|| self.proc_cmdline.take());
+        self.cmdline_policy = other.cmdline_policy.or_else(|| self.cmdline_policy.take());
+        self.crypttab = other.crypttab.or_else(|| self.crypttab.take());
+        self.verity = other.verity.or_else(|| self.verity.take());
+    }
+}
+
+impl CmdlineOption {
+    fn fragments(&self) -> Vec<InlineOrPath> {
+        match self {
+            CmdlineOption::Path(path) => vec![InlineOrPath::Path(path.clone())],
+            CmdlineOption::Fragments(fragments) => fragments.clone(),
+            CmdlineOption::Full {
+                inline, fragments, ..
+            } => inline
+                .iter()
+                .map(|inline| InlineOrPath::Inline {
+                    inline: inline.clone(),
+                })
+                .chain(fragments.iter().cloned())
+                .collect(),
+        }
+    }
+
+    fn appends(&self) -> bool {
+        match self {
+            CmdlineOption::Full { append, .. } => *append,
+            _ => false,
+        }
+    }
+
+    fn removed(&self) -> &[String] {
+        match self {
+            CmdlineOption::Full { remove, .. } => remove,
+            _ => &[],
+        }
+    }
+}
+
+impl Kernel {
+    // Flavors with the same name are replaced as a whole
+    fn merge(&mut self, other: Kernel) {
+        self.options.merge(other.options);
+        self.flavors.extend(other.flavors);
+    }
+
+    // Every flavor must extend an existing one, without cycles
+    fn check_extends(&self, kernel: &str) -> Result<(), Error> {
+        for name in self.flavors.keys() {
+            let mut chain = vec![name.as_str()];
+
+            while let Some(parent) = &self.flavors[*chain.last().unwrap()].extends {
+                if !self.flavors.contains_key(parent) {
+                    return Err(anyhow!(
+                        "Flavor {}.{} extends {}, which doesn't exist",
+                        kernel,
+                        chain.last().unwrap(),
+                        parent
+                    ));
+                }
+
+                if chain.contains(&parent.as_str()) {
+                    chain.push(parent);
+                    return Err(anyhow!(
+                        "Flavors of {} extend each other in a cycle: {}",
+                        kernel,
+                        chain.join(" -> ")
+                    ));
+                }
+
+                chain.push(parent);
+            }
+        }
+
+        Ok(())
+    }
+}
+
 impl Config {
-    fn maybe_copy_flavors(mut self) -> Result<Self, Error> {
-        if !self.kernels.contains_key("any") {
-            return Ok(self);
+    /// Main config file followed by its drop-ins, in the order they're merged.
+    ///
+    /// Drop-ins are the '*.yaml' files in 'conf.d' next to the main config
+    /// and in the vendor directory, sorted by file name. A drop-in in the
+    /// former replaces a vendor one with the same name. Vendor drop-ins are
+    /// only for the system config, not for one given with '-c'.
+    pub fn files(config_path: impl AsRef<Path>) -> Result<Vec<PathBuf>, Error> {
+        let config_path = config_path.as_ref();
+        let local_dir = config_path
+            .parent()
+            .unwrap_or_else(|| Path::new("."))
+            .join("conf.d");
+
+        let default = Path::new(DEFAULT_CONFIG);
+        let is_default = config_path == default
+            || config_path
+                .canonicalize()
+                .is_ok_and(|path| default.canonicalize().is_ok_and(|default| path == default));
+
+        let mut dirs = vec![local_dir.as_path()];
+        if is_default {
+            dirs.insert(0, Path::new(VENDOR_DROP_IN_DIR));
+        }
+
+        let mut drop_ins = BTreeMap::new();
+        for dir in dirs {
+            let pattern = dir.join("*.yaml");
+            for path in glob::glob(&pattern.to_string_lossy())? {
+                let path = path?;
+                if let Some(name) = path.file_name() {
+                    drop_ins.insert(name.to_owned(), path);
+                }
+            }
+        }
+
+        let mut files = vec![config_path.to_path_buf()];
+        files.extend(drop_ins.into_values());
+        Ok(files)
+    }
+
+    fn parse(path: &Path) -> Result<Self, Error> {
+        let contents = std::fs::read_to_string(path).map_err(|e| AppError::IoError {
+            path: path.into(),
+            source: e,
+        })?;
+
+        // Empty files (or only comments) are fine, e.g. a commented out drop-in
+        let empty = match YamlLoader::load_from_str(&contents) {
+            Ok(documents) => documents.first().is_none_or(Yaml::is_null),
+            Err(_) => false,
+        };
+
+        if empty {
+            return Ok(Self::default());
+        }
+
+        yaml::from_str(&contents).map_err(|e| anyhow!("{}: {}", path.to_string_lossy(), e))
+    }
+
+    // Kernels not seen before are added, the others are merged
+    fn merge(&mut self, other: Config) {
+        self.defaults.merge(other.defaults);
+        self.templates.extend(other.templates);
+
+        for (name, kernel) in other.kernels {
+            match self.kernels.get_mut(&name) {
+                Some(current) => current.merge(kernel),
+                None => {
+                    self.kernels.insert(name, kernel);
+                }
+            }
         }
+    }
 
-        let any = self.kernels["any"].clone();
+    // Explicitly configured kernels are left alone, every other installed
+    // kernel takes the first template (by name) that matches it
+    fn apply_templates(mut self) -> Result<Self, Error> {
+        let mut templates: Vec<_> = std::mem::take(&mut self.templates).into_iter().collect();
 
-        for kernel in glob::glob("/boot/vmlinuz-*")? {
-            let kernel = kernel?;
-            let kernel = &kernel.to_string_lossy()[14..];
+        // Legacy 'any' kernel, a template matching everything (tried last)
+        if let Some(any) = self.kernels.remove("any") {
+            log::warn!("Kernel 'any' is deprecated, use a template with 'match: \"*\"'");
+            templates.push(("any".into(), Template::from_any(any)));
+        }
+
+        if templates.is_empty() {
+            return Ok(self);
+        }
 
-            self.kernels
-                .entry(kernel.to_owned())
-                .or_insert_with(|| any.clone());
+        let mut compiled = Vec::with_capacity(templates.len());
+        for (template_name, template) in &templates {
+            compiled.push((template_name, template, template.compile(template_name)?));
         }
 
-        self.kernels.remove("any");
+        let names: Vec<_> = self.installed.iter().map(|k| k.name.clone()).collect();
+        for name in names {
+            if self.kernels.contains_key(&name) {
+                continue;
+            }
+
+            for (template_name, template, matcher) in &compiled {
+                if let Some(kernel) = template.instantiate(matcher, &name) {
+                    log::debug!("Kernel {} created from template {}", name, template_name);
+                    self.kernels.insert(name, kernel);
+                    break;
+                }
+            }
+        }
 
         Ok(self)
     }
 
     pub fn from_matches(matches: &ArgMatches) -> Result<Self, Error> {
-        let config_path = matches.value_of("config").unwrap();
-        let contents = std::fs::read_to_string(config_path)?;
-        let mut config: Self = yaml::from_str(&contents)?;
-        config.location = Path::new(config_path)
+        Self::from_path(matches.value_of("config").unwrap())
+    }
+
+    pub fn from_path(config_path: impl AsRef<Path>) -> Result<Self, Error> {
+        let config_path = config_path.as_ref();
+        let mut config = Self::parse(config_path)?;
+
+        for drop_in in Self::files(config_path)?.iter().skip(1) {
+            log::debug!("Merging drop-in {}", drop_in.to_string_lossy());
+            config.merge(Self::parse(drop_in)?);
+        }
+
+        // Relative paths, even those in drop-ins, are relative to the main config
+        config.location = config_path
             .parent()
+            .filter(|p| !p.as_os_str().is_empty())
             .unwrap_or(&std::env::current_dir()?)
             .canonicalize()?;
 
-        config.maybe_copy_flavors()
+        for (name, template) in &config.templates {
+            template
+                .kernel
+                .check_extends(&format!("template {}", name))?;
+        }
+
+        for (name, kernel) in &config.kernels {
+            kernel.check_extends(name)?;
+        }
+
+        config.installed = kernels::installed()?;
+        config.apply_templates()
+    }
+
+    // The flavor followed by the ones it extends, nearest first
+    fn flavor_chain(&self, kernel: &str, flavor: &str) -> Vec<&Flavor> {
+        let flavors = &self.kernels[kernel].flavors;
+        let mut chain = vec![&flavors[flavor]];
+
+        // Cycles and unknown parents are rejected when loading
+        while let Some(parent) = chain.last().unwrap().extends.as_ref() {
+            chain.push(&flavors[parent]);
+        }
+
+        chain
+    }
+
+    // Options of the flavor (and the ones it extends), its kernel and
+    // defaults, the most specific first
+    fn options_chain(&self, kernel: &str, flavor: &str) -> Vec<&Options> {
+        let mut chain: Vec<_> = self
+            .flavor_chain(kernel, flavor)
+            .into_iter()
+            .map(|flavor| &flavor.options)
+            .collect();
+
+        chain.push(&self.kernels[kernel].options);
+        chain.push(&self.defaults);
+        chain
+    }
+
+    // Look for an option in the flavor (and the ones it extends), then in
+    // its kernel and then in defaults
+    fn lookup<T: Clone>(
+        &self,
+        kernel: &str,
+        flavor: &str,
+        get: impl Fn(&Options) -> &Option<T>,
+    ) -> Option<T> {
+        self.options_chain(kernel, flavor)
+            .into_iter()
+            .find_map(|options| get(options).clone())
+    }
+
+    /// Values for the placeholders of the formats used by an entry
+    fn vars(&self, kernel: &str, flavor: &str) -> Vars {
+        let mut vars = self.base_vars(kernel, flavor);
+        if let Some(version) = self.version(kernel, flavor) {
+            vars.insert("version".into(), version);
+        }
+
+        vars
+    }
+
+    // Placeholders that don't depend on the linux image (used to find it)
+    fn base_vars(&self, kernel: &str, flavor: &str) -> Vars {
+        let mut vars = self.kernels[kernel].captures.clone();
+        vars.insert("kernel".into(), kernel.into());
+        vars.insert("flavor".into(), flavor.into());
+        vars
+    }
+
+    /// Kernel release of an entry, read from its linux image if possible
+    pub fn version(&self, kernel: &str, flavor: &str) -> Option<String> {
+        self.linux_path(kernel, flavor)
+            .ok()
+            .and_then(|linux| kernels::image_release(&linux))
+            .or_else(|| self.release(kernel).map(str::to_owned))
+    }
+
+    /// Release of an installed kernel, from its modules directory
+    fn release(&self, kernel: &str) -> Option<&str> {
+        self.installed
+            .iter()
+            .find(|k| k.name == kernel)
+            .and_then(|k| k.release.as_deref())
     }
 
     pub fn is_enabled(&self, kernel: &str, flavor: &str) -> bool {
-        self.kernels[kernel].flavors[flavor]
-            .enabled
-            .clone()
-            .unwrap_or(true)
+        self.kernels[kernel].flavors[flavor].enabled.unwrap_or(true)
+    }
+
+    // Fields to override, merged from defaults up to the flavor
+    fn os_release_fields(&self, kernel: &str, flavor: &str) -> BTreeMap<String, String> {
+        let mut fields = BTreeMap::new();
+        for options in self.options_chain(kernel, flavor).into_iter().rev() {
+            if let Some(overrides) = &options.os_release_fields {
+                fields.extend(overrides.clone());
+            }
+        }
+
+        fields
     }
 
-    pub fn os_release_path(&self, kernel: &str, flavor: &str) -> Result<PathBuf, AppError> {
-        let os_release = self.kernels[kernel].flavors[flavor]
-            .os_release
-            .clone()
-            .unwrap_or_else(|| "/etc/os-release".into());
+    pub fn os_release_source(&self, kernel: &str, flavor: &str) -> Result<Source, AppError> {
+        let vars = self.vars(kernel, flavor);
+        let os_release = match self.lookup(kernel, flavor, |o| &o.os_release) {
+            Some(path) => render(kernel, flavor, "os-release", &path, &vars)?,
+            None => "/etc/os-release".into(),
+        };
 
         // Fallback to '/usr/lib/os-release' if the other two doesn't exist
         let os_release = check_file(&self.location, os_release)
-            .or_else(|_| check_file(&self.location, "/usr/lib/os-release"))?;
+            .or_else(|_| check_file(&self.location, "/usr/lib/os-release"));
 
-        if let Some(title) = &self.kernels[kernel].flavors[flavor].title {
-            let base_os_release = File::open(&os_release).expect("Wtf? How this failed?");
-            let base_os_release = BufReader::new(base_os_release);
-            let (path, mut temp) = temp::temp_file(&format!("{}-{}-os-release", kernel, flavor))?;
+        let title = self.lookup(kernel, flavor, |o| &o.title);
+        let fields = self.os_release_fields(kernel, flavor);
+        if title.is_none() && fields.is_empty() {
+            if let Ok(os_release) = os_release {
+                return Ok(Source::File(os_release));
+            }
+        }
 
-            for line in base_os_release.lines() {
-                let line = line.map_err(|e| AppError::IoError {
-                    path: os_release.clone(),
+        // Only an os-release rewritten for the flavor gets its ID suffixed,
+        // not the generic one used as is
+        let rewritten = title.is_some() || !fields.is_empty();
+        let base = os_release.ok();
+        let mut contents = match &base {
+            Some(path) => {
+                let contents = std::fs::read_to_string(path).map_err(|e| AppError::IoError {
+                    path: path.clone(),
                     source: e,
                 })?;
 
-                let splitted: Vec<_> = line.split('=').map(str::trim).collect();
-                match (splitted[0], splitted[1]) {
-                    (key @ "PRETTY_NAME", _) => {
-                        writeln!(temp, "{}=\"{}\"", key, title).map_err(|e| AppError::IoError {
-                            path: path.clone(),
-                            source: e,
-                        })?;
-                    }
+                OsRelease::parse(&contents)
+            }
+            None => {
+                log::warn!(
+                    "No os-release found for {}.{}, using a generic one",
+                    kernel,
+                    flavor
+                );
+                OsRelease::generic()
+            }
+        };
 
-                    (key @ "ID", value) => {
-                        let mut value = value.to_owned();
-                        value.push_str(&format!("-{}", flavor));
-                        writeln!(temp, "{}={}", key, value).map_err(|e| AppError::IoError {
-                            path: path.clone(),
-                            source: e,
-                        })?;
-                    }
+        if let Some(title) = title {
+            let title = format::render(&title, &vars)
+                .map_err(|e| template_error(kernel, flavor, "title", e))?;
+            contents.set("PRETTY_NAME", title);
+        }
 
-                    (key, value) => {
-                        writeln!(temp, "{}={}", key, value).map_err(|e| AppError::IoError {
-                            path: path.clone(),
-                            source: e,
-                        })?;
-                    }
+        let id_suffix = self
+            .lookup(kernel, flavor, |o| &o.id_suffix)
+            .unwrap_or(true);
+        if let Some(id) = contents.get("ID").filter(|_| rewritten && id_suffix) {
+            let id = format!("{}-{}", id, flavor);
+            contents.set("ID", id);
+        }
+
+        // Overrides go last, they can even replace ID or PRETTY_NAME
+        for (key, value) in fields {
+            let value = format::render(&value, &vars)
+                .map_err(|e| template_error(kernel, flavor, "os-release-fields", e))?;
+
+            match value.as_str() {
+                "" => contents.remove(&key),
+                _ => contents.set(&key, value),
+            }
+        }
+
+        Ok(Source::Generated {
+            contents: contents.to_string().into_bytes(),
+            base,
+        })
+    }
+
+    // Every '*.conf' in the cmdline drop-in directory, sorted by name
+    fn cmdline_drop_ins(&self) -> Vec<PathBuf> {
+        let pattern = self.location.join(CMDLINE_DROP_IN_DIR).join("*.conf");
+        let mut drop_ins: Vec<_> = glob::glob(&pattern.to_string_lossy())
+            .map(|paths| paths.filter_map(Result::ok).collect())
+            .unwrap_or_default();
+
+        drop_ins.sort();
+        drop_ins
+    }
+
+    // Cmdline of the system, for entries that don't set the whole cmdline
+    fn fallback_cmdline(&self, kernel: &str, flavor: &str) -> Result<Option<Cmdline>, AppError> {
+        let use_proc = self
+            .lookup(kernel, flavor, |o| &o.proc_cmdline)
+            .unwrap_or(false);
+
+        let mut candidates: Vec<&Path> = FALLBACK_CMDLINES.iter().map(Path::new).collect();
+        if use_proc {
+            candidates.push(Path::new("/proc/cmdline"));
+        }
+
+        let (path, cmdline) = match first_cmdline(&candidates)? {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+
+        log::info!(
+            "Using cmdline from {} for {}.{}",
+            path.display(),
+            kernel,
+            flavor
+        );
+        Ok(Some(cmdline))
+    }
+
+    // rd.luks.* and root= params for an encrypted root
+    fn crypttab_params(&self, kernel: &str, flavor: &str) -> Vec<String> {
+        if !self
+            .lookup(kernel, flavor, |o| &o.crypttab)
+            .unwrap_or(false)
+        {
+            return Vec::new();
+        }
+
+        let devices = system::root_luks_devices();
+        if devices.is_empty() {
+            log::warn!(
+                "Root isn't encrypted, ignoring crypttab for {}.{}",
+                kernel,
+                flavor
+            );
+            return Vec::new();
+        }
+
+        let mut params = Vec::new();
+        for device in devices {
+            let uuid = match device.uuid {
+                Some(uuid) => uuid,
+                None => {
+                    log::warn!("Couldn't find the LUKS UUID of {}", device.name);
+                    continue;
                 }
+            };
+
+            params.push(format!("rd.luks.name={}={}", uuid, device.name));
+            if let Some(options) = device.options {
+                params.push(format!("rd.luks.options={}={}", uuid, options));
             }
+        }
 
-            return Ok(path);
+        if let Some(name) = system::root_mapper_name() {
+            params.push(format!("root=/dev/mapper/{}", name));
         }
 
-        Ok(os_release)
+        params
     }
 
-    pub fn cmdline_path(&self, kernel: &str, flavor: &str) -> Result<Option<PathBuf>, AppError> {
-        let kernel_entry = &self.kernels[kernel];
-        let cmdline = kernel_entry.flavors[flavor]
-            .cmdline
-            .clone()
-            .or_else(|| kernel_entry.cmdline.clone());
+    // roothash= and usrhash= params, read from the configured files
+    fn verity_params(&self, kernel: &str, flavor: &str) -> Result<Vec<String>, AppError> {
+        let verity = match self.lookup(kernel, flavor, |o| &o.verity) {
+            Some(verity) => verity,
+            None => return Ok(Vec::new()),
+        };
 
-        match cmdline {
-            Some(InlineOrPath::Path(path)) => {
-                let path = path.replace(kernel, flavor);
-                Ok(Some(check_file(&self.location, path)?))
+        let vars = self.vars(kernel, flavor);
+        let mut params = Vec::new();
+        for (key, path) in &[("roothash", verity.roothash), ("usrhash", verity.usrhash)] {
+            if let Some(path) = path {
+                let path = render(kernel, flavor, "verity", path, &vars)?;
+                let path = check_file(&self.location, path)?;
+                params.push(format!("{}={}", key, read_root_hash(&path)?));
             }
+        }
 
-            Some(InlineOrPath::Inline { inline: contents }) => {
-                let (path, mut temp) = temp::temp_file(&format!("{}-{}-cmdline", kernel, flavor))?;
+        Ok(params)
+    }
 
-                write!(temp, "{}", contents).map_err(|e| AppError::IoError {
-                    path: path.clone(),
-                    source: e,
-                })?;
+    /// Final cmdline of an entry. Drop-ins come first, then the cmdline of
+    /// the least specific level that doesn't append (or the one of the
+    /// system if all of them append) followed by the ones appending to it,
+    /// each level removing params from the previous ones. The params for an
+    /// encrypted root and the dm-verity hashes go last, replacing any set
+    /// before.
+    pub fn cmdline(&self, kernel: &str, flavor: &str) -> Result<Option<Cmdline>, AppError> {
+        let mut levels = Vec::new();
+        for options in self.options_chain(kernel, flavor) {
+            if let Some(cmdline) = &options.cmdline {
+                levels.push(cmdline);
+                if !cmdline.appends() {
+                    break;
+                }
+            }
+        }
+
+        let base = match levels.last() {
+            Some(level) if !level.appends() => {
+                log::info!("Using cmdline from the config for {}.{}", kernel, flavor);
+                None
+            }
+            _ => self.fallback_cmdline(kernel, flavor)?,
+        };
+
+        let drop_ins = self.cmdline_drop_ins();
+        let mut generated = self.crypttab_params(kernel, flavor);
+        generated.extend(self.verity_params(kernel, flavor)?);
+        if levels.is_empty() && base.is_none() && drop_ins.is_empty() && generated.is_empty() {
+            log::info!(
+                "No cmdline for {}.{}, the stub will use its own",
+                kernel,
+                flavor
+            );
+            return Ok(None);
+        }
 
-                Ok(Some(path))
+        // Contents can use placeholders too, e.g. 'root=UUID={root-uuid}'
+        let vars = self.vars(kernel, flavor);
+        let expand = |contents: &str| {
+            format::render(contents, &vars)
+                .map_err(|e| template_error(kernel, flavor, "cmdline", e))
+        };
+        let read = |path: &Path| {
+            let contents = std::fs::read_to_string(path).map_err(|e| AppError::IoError {
+                path: path.into(),
+                source: e,
+            })?;
+
+            expand(&contents)
+        };
+
+        let mut cmdline = Cmdline::default();
+        for drop_in in &drop_ins {
+            cmdline.extend(&read(drop_in)?);
+        }
+
+        if let Some(base) = base {
+            cmdline.extend(&base.to_string());
+        }
+
+        for level in levels.iter().rev() {
+            for param in level.removed() {
+                cmdline.remove(param);
             }
 
-            _ => Ok(None),
+            for fragment in level.fragments() {
+                match fragment {
+                    InlineOrPath::Path(path) => {
+                        let path = render(kernel, flavor, "cmdline", &path, &vars)?;
+                        cmdline.extend(&read(&check_file(&self.location, path)?)?);
+                    }
+                    InlineOrPath::Inline { inline } => cmdline.extend(&expand(&inline)?),
+                }
+            }
         }
+
+        for param in &generated {
+            match param_name(param) {
+                name @ ("root" | "roothash" | "usrhash") => cmdline.remove(name),
+                _ => cmdline.remove(param),
+            }
+        }
+
+        cmdline.extend(&generated.join(" "));
+
+        let policy = self
+            .lookup(kernel, flavor, |o| &o.cmdline_policy)
+            .unwrap_or_default();
+
+        cmdline.lint(&policy).map_err(AppError::Cmdline)?;
+
+        Ok(Some(cmdline))
+    }
+
+    pub fn cmdline_source(&self, kernel: &str, flavor: &str) -> Result<Option<Source>, AppError> {
+        let cmdline = self.cmdline(kernel, flavor)?;
+        Ok(cmdline.map(|cmdline| Source::Generated {
+            contents: cmdline.to_string().into_bytes(),
+            base: None,
+        }))
     }
 
     pub fn splash_image_path(
@@ -293,15 +1129,17 @@ impl Config {
         kernel: &str,
         flavor: &str,
     ) -> Result<Option<PathBuf>, AppError> {
-        let kernel_entry = &self.kernels[kernel];
-        let splash_image = kernel_entry.flavors[flavor]
-            .splash_image
-            .clone()
-            .or_else(|| kernel_entry.splash_image.clone());
+        let splash_image = self.lookup(kernel, flavor, |o| &o.splash_image);
 
         match splash_image {
             Some(path) => {
-                let path = path.replace(kernel, flavor);
+                let path = render(
+                    kernel,
+                    flavor,
+                    "splash-image",
+                    &path,
+                    &self.vars(kernel, flavor),
+                )?;
                 Ok(Some(check_splash(&self.location, path)?))
             }
             None => Ok(None),
@@ -309,63 +1147,61 @@ impl Config {
     }
 
     pub fn linux_path(&self, kernel: &str, flavor: &str) -> Result<PathBuf, AppError> {
-        let kernel_entry = &self.kernels[kernel];
-        let linux = kernel_entry.flavors[flavor]
-            .linux
-            .clone()
-            .or_else(|| kernel_entry.linux.clone());
+        let linux = self.lookup(kernel, flavor, |o| &o.linux);
 
         match linux {
             Some(path) => {
-                let path = path.replace(kernel, flavor);
+                let path = render(
+                    kernel,
+                    flavor,
+                    "linux",
+                    &path,
+                    &self.base_vars(kernel, flavor),
+                )?;
                 check_file(&self.location, path)
             }
-            None => check_file(&self.location, format!("/boot/vmlinuz-{}", kernel)),
+            // Not every distro copies the image to /boot anymore
+            None => check_file(&self.location, format!("/boot/vmlinuz-{}", kernel)).or_else(|e| {
+                match self.installed.iter().find(|k| k.name == kernel) {
+                    Some(installed) => check_file(&self.location, &installed.image),
+                    None => Err(e),
+                }
+            }),
         }
     }
 
-    pub fn initrd_path(&self, kernel: &str, flavor: &str) -> Result<PathBuf, AppError> {
-        let initrd = self.kernels[kernel].flavors[flavor]
-            .initrd
-            .clone()
-            .unwrap_or(populate_initrd(kernel, flavor)?);
+    pub fn initrd_source(&self, kernel: &str, flavor: &str) -> Result<Source, AppError> {
+        let initrd = match self.lookup(kernel, flavor, |o| &o.initrd) {
+            Some(initrd) => initrd,
+            None => populate_initrd(kernel, flavor)?,
+        };
 
         match initrd {
             OneOrMany::One(path) => {
-                let path = path.replace(kernel, flavor);
-                check_file(&self.location, path)
+                let path = render(kernel, flavor, "initrd", &path, &self.vars(kernel, flavor))?;
+                Ok(Source::File(check_file(&self.location, path)?))
             }
             OneOrMany::Many(paths) => {
-                let (path, mut temp) =
-                    temp::temp_file(&format!("{}-{}-initrd.img", kernel, flavor))?;
-
-                for initrd in &paths {
-                    let initrd = initrd.replace(kernel, flavor);
-                    let contents = std::fs::read(&initrd).map_err(|e| AppError::IoError {
-                        path: initrd.clone(),
-                        source: e,
-                    })?;
-
-                    temp.write_all(&contents).map_err(|e| AppError::IoError {
-                        path: initrd.clone(),
-                        source: e,
-                    })?;
+                let vars = self.vars(kernel, flavor);
+                let mut resolved = Vec::with_capacity(paths.len());
+                for path in &paths {
+                    let path = render(kernel, flavor, "initrd", path, &vars)?;
+                    resolved.push(check_file(&self.location, path)?);
                 }
 
-                Ok(path)
+                Ok(Source::Concat(resolved))
             }
         }
     }
 
     pub fn efistub_path(&self, kernel: &str, flavor: &str) -> Result<PathBuf, AppError> {
-        let kernel_entry = &self.kernels[kernel];
-        let efistub = kernel_entry.flavors[flavor]
-            .efistub
-            .clone()
-            .or_else(|| kernel_entry.efistub.clone());
+        let efistub = self.lookup(kernel, flavor, |o| &o.efistub);
 
         match efistub {
-            Some(path) => check_file(&self.location, path),
+            Some(path) => {
+                let path = render(kernel, flavor, "efistub", &path, &self.vars(kernel, flavor))?;
+                check_file(&self.location, path)
+            }
             None => check_file(
                 &self.location,
                 "/usr/lib/systemd/boot/efi/linuxx64.efi.stub",
@@ -374,9 +1210,245 @@ impl Config {
     }
 
     pub fn output_path(&self, kernel: &str, flavor: &str) -> Result<PathBuf, AppError> {
-        Ok(self.kernels[kernel].flavors[flavor]
-            .output
-            .clone()
-            .replace(kernel, flavor))
+        match self.lookup(kernel, flavor, |o| &o.output) {
+            Some(output) => render(
+                kernel,
+                flavor,
+                "output",
+                &output,
+                &self.vars(kernel, flavor),
+            ),
+            None => Err(AppError::MissingOutput),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_cmdline_takes_the_first_existing() {
+        let dir = tempfile::tempdir().unwrap();
+        let etc = dir.path().join("etc-cmdline");
+        let lib = dir.path().join("lib-cmdline");
+        let proc = dir.path().join("proc-cmdline");
+        std::fs::write(&lib, "root=/dev/sda2 quiet\n").unwrap();
+        std::fs::write(&proc, "root=/dev/sda3 rw\n").unwrap();
+
+        let candidates = [etc.as_path(), lib.as_path(), proc.as_path()];
+        let (path, cmdline) = first_cmdline(&candidates).unwrap().unwrap();
+        assert_eq!(path, lib.as_path());
+        assert_eq!(cmdline.to_string(), "root=/dev/sda2 quiet");
+
+        std::fs::write(&etc, "# comment\nroot=/dev/sda1 rw\n").unwrap();
+        let (path, cmdline) = first_cmdline(&candidates).unwrap().unwrap();
+        assert_eq!(path, etc.as_path());
+        assert_eq!(cmdline.to_string(), "root=/dev/sda1 rw");
+    }
+
+    #[test]
+    fn first_cmdline_strips_bootloader_params() {
+        let dir = tempfile::tempdir().unwrap();
+        let proc = dir.path().join("proc-cmdline");
+        let contents = "BOOT_IMAGE=/vmlinuz-linux root=/dev/sda3 initrd=\\initramfs.img rw\n";
+        std::fs::write(&proc, contents).unwrap();
+
+        let (_, cmdline) = first_cmdline(&[proc.as_path()]).unwrap().unwrap();
+        assert_eq!(cmdline.to_string(), "root=/dev/sda3 rw");
+
+        let missing = dir.path().join("missing");
+        assert!(first_cmdline(&[missing.as_path()]).unwrap().is_none());
+    }
+
+    fn glob_template(glob: &str, exclude: &[&str]) -> Template {
+        Template {
+            glob: Some(glob.into()),
+            regex: None,
+            exclude: exclude.iter().map(|e| e.to_string()).collect(),
+            kernel: yaml::from_str("flavors: {}").unwrap(),
+        }
+    }
+
+    // Whether 'match' takes the same names as glob::Pattern, and its captures
+    fn captures(glob: &str, name: &str) -> Option<Vec<String>> {
+        let template = glob_template(glob, &[]);
+        let matcher = template.compile("test").unwrap();
+        let kernel = template.instantiate(&matcher, name);
+
+        let pattern = glob::Pattern::new(glob).unwrap();
+        assert_eq!(kernel.is_some(), pattern.matches(name), "{} {}", glob, name);
+
+        let kernel = kernel?;
+        let mut captures: Vec<_> = kernel
+            .captures
+            .iter()
+            .filter_map(|(k, v)| Some((k.parse::<usize>().ok()?, v.clone())))
+            .collect();
+        captures.sort();
+        Some(captures.into_iter().map(|(_, v)| v).collect())
+    }
+
+    #[test]
+    fn glob_wildcards() {
+        assert_eq!(captures("linux-lts*", "linux-lts510").unwrap(), ["510"]);
+        assert_eq!(captures("linux-lts*", "linux-lts").unwrap(), [""]);
+        assert_eq!(captures("linux?*", "linux-zen").unwrap(), ["-", "zen"]);
+        assert_eq!(captures("**", "linux").unwrap(), ["linux"]);
+        assert!(captures("linux-lts*", "linux-zen").is_none());
+        assert!(captures("linux.*", "linuxx").is_none());
+    }
+
+    #[test]
+    fn glob_classes() {
+        assert_eq!(captures("linux-[lz]*", "linux-zen").unwrap(), ["z", "en"]);
+        assert_eq!(captures("linux-[lz]*", "linux-lts").unwrap(), ["l", "ts"]);
+        assert!(captures("linux-[lz]*", "linux-hardened").is_none());
+
+        assert_eq!(captures("linux-[!lz]*", "linux-rt").unwrap(), ["r", "t"]);
+        assert!(captures("linux-[!lz]*", "linux-zen").is_none());
+
+        assert_eq!(captures("linux[0-9]", "linux5").unwrap(), ["5"]);
+        assert!(captures("linux[0-9]", "linuxa").is_none());
+        assert!(captures("linux[9-0]", "linux5").is_none());
+        assert_eq!(captures("linux[!9-0]", "linux5").unwrap(), ["5"]);
+        assert_eq!(captures("a[-x]", "a-").unwrap(), ["-"]);
+        assert_eq!(captures("a[x-]", "a-").unwrap(), ["-"]);
+        assert_eq!(captures("a[^]", "a^").unwrap(), ["^"]);
+    }
+
+    #[test]
+    fn glob_escapes() {
+        assert_eq!(captures("linux[*]", "linux*").unwrap(), ["*"]);
+        assert!(captures("linux[*]", "linux-zen").is_none());
+        assert_eq!(captures("a[?]", "a?").unwrap(), ["?"]);
+        assert_eq!(captures("a[[]", "a[").unwrap(), ["["]);
+        assert_eq!(captures("a[]]", "a]").unwrap(), ["]"]);
+        assert_eq!(captures("a[!]]", "ab").unwrap(), ["b"]);
+        assert!(captures("a[!]]", "a]").is_none());
+        assert!(captures("a\\b", "a\\b").is_some());
+        assert!(captures("a.b+c", "a.b+c").is_some());
+    }
+
+    #[test]
+    fn glob_excludes_and_invalid() {
+        let template = glob_template("linux-lts*", &["linux-lts-old"]);
+        let matcher = template.compile("test").unwrap();
+        assert!(template.instantiate(&matcher, "linux-lts").is_some());
+        assert!(template.instantiate(&matcher, "linux-lts-old").is_none());
+
+        assert!(glob_template("linux-[lz", &[]).compile("test").is_err());
+        assert!(glob_template("linux***", &[]).compile("test").is_err());
+    }
+
+    #[test]
+    fn empty_drop_ins() {
+        let dir = tempfile::tempdir().unwrap();
+        for contents in &["", "\n", "# linux:\n#   flavors: {}\n", "---\n"] {
+            let path = dir.path().join("empty.yaml");
+            std::fs::write(&path, contents).unwrap();
+
+            let config = Config::parse(&path).unwrap();
+            assert!(config.kernels.is_empty(), "{:?}", contents);
+        }
+
+        let path = dir.path().join("invalid.yaml");
+        std::fs::write(&path, "linux: [\n").unwrap();
+        assert!(Config::parse(&path).is_err());
+    }
+
+    #[test]
+    fn drop_ins_merge_os_release_fields() {
+        let mut kernel: Kernel =
+            yaml::from_str("os-release-fields: {A: a, B: b}\nflavors: {}").unwrap();
+        let drop_in: Kernel =
+            yaml::from_str("os-release-fields: {B: c, D: d}\nflavors: {}").unwrap();
+        kernel.merge(drop_in);
+
+        let fields = kernel.options.os_release_fields.unwrap();
+        let expected: BTreeMap<_, _> = [("A", "a"), ("B", "c"), ("D", "d")]
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        assert_eq!(fields, expected);
+    }
+
+    fn extends_error(flavors: &str) -> String {
+        let kernel: Kernel = yaml::from_str(&format!("flavors: {}", flavors)).unwrap();
+        kernel.check_extends("linux").unwrap_err().to_string()
+    }
+
+    #[test]
+    fn extends_cycles_and_missing_parents() {
+        // Flavors aren't ordered, the cycle can be found from either one
+        let error = extends_error("{a: {extends: b}, b: {extends: a}}");
+        let cycles = ["a -> b -> a", "b -> a -> b"];
+        assert!(cycles.iter().any(|c| error.ends_with(c)), "{}", error);
+
+        let error = extends_error("{a: {extends: a}}");
+        assert!(error.ends_with("a cycle: a -> a"), "{}", error);
+
+        let error = extends_error("{a: {extends: b}, b: {extends: c}}");
+        assert_eq!(error, "Flavor linux.b extends c, which doesn't exist");
+
+        let kernel: Kernel = yaml::from_str("flavors: {a: {}, b: {extends: a}}").unwrap();
+        assert!(kernel.check_extends("linux").is_ok());
+    }
+
+    #[test]
+    fn extends_closest_parent_wins() {
+        let config: Config = yaml::from_str(
+            "linux:\n  title: kernel\n  flavors:\n    \
+             a: {title: grandparent, output: /boot/a.efi}\n    \
+             b: {extends: a, title: parent}\n    \
+             c: {extends: b}\n",
+        )
+        .unwrap();
+
+        let chain: Vec<_> = config
+            .flavor_chain("linux", "c")
+            .iter()
+            .map(|flavor| flavor.extends.clone())
+            .collect();
+        assert_eq!(chain, [Some("b".into()), Some("a".into()), None]);
+
+        let title = config.lookup("linux", "c", |options| &options.title);
+        assert_eq!(title.as_deref(), Some("parent"));
+        let output = config.lookup("linux", "c", |options| &options.output);
+        assert!(output.is_some());
+        let title = config.lookup("linux", "a", |options| &options.title);
+        assert_eq!(title.as_deref(), Some("grandparent"));
+    }
+
+    #[test]
+    fn root_hash_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("image.roothash");
+        let hash = "8f5a2ec3b5c5e2b9d3b1a6e4c0f1d2e3a4b5c6d7e8f90112233445566778899a";
+        let read = |contents: &str| {
+            std::fs::write(&path, contents).unwrap();
+            read_root_hash(&path)
+        };
+
+        assert_eq!(read(&format!("{}\n", hash)).unwrap(), hash);
+        assert_eq!(read(&hash.to_uppercase()).unwrap(), hash);
+
+        let veritysetup = format!(
+            "VERITY header information for image.verity\n\
+             UUID:            \t0a1b2c3d-0000-4000-8000-000000000001\n\
+             Hash type:       \t1\n\
+             Salt:            \t{}\n\
+             Root hash:      \t{}\n",
+            "00".repeat(32),
+            hash
+        );
+        assert_eq!(read(&veritysetup).unwrap(), hash);
+
+        let odd = &hash[1..];
+        let not_hex = hash.replace('a', "g");
+        for invalid in &["8f5a2ec3", "not a hash", odd, &not_hex] {
+            let error = read(invalid).unwrap_err();
+            assert!(matches!(error, AppError::InvalidRootHash(_)), "{}", invalid);
+        }
     }
 }
@@ -15,12 +15,62 @@
 // You should have received a copy of the GNU General Public License
 // along with gen-uki.  If not, see <http://www.gnu.org/licenses/>.
 
+use std::io::ErrorKind;
 use std::path::PathBuf;
 
+use anyhow::Error as AnyError;
 use thiserror::Error;
 
+use crate::cmdline::CmdlineError;
+use crate::format::FormatError;
+
+/// Broad kind of an error, each one has its own exit code (see the README)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+    Other,
+    Config,
+    MissingInput,
+    InvalidInput,
+    Tool,
+    Permission,
+}
+
+impl ErrorClass {
+    /// Exit code 6 is reserved for signing failures
+    pub fn exit_code(self) -> i32 {
+        match self {
+            ErrorClass::Other => 1,
+            ErrorClass::Config => 2,
+            ErrorClass::MissingInput => 3,
+            ErrorClass::InvalidInput => 4,
+            ErrorClass::Tool => 5,
+            ErrorClass::Permission => 7,
+        }
+    }
+}
+
+// Class of an I/O error, e.g. a file that doesn't exist is a missing input
+fn io_class(error: &std::io::Error) -> ErrorClass {
+    match error.kind() {
+        ErrorKind::NotFound => ErrorClass::MissingInput,
+        ErrorKind::PermissionDenied => ErrorClass::Permission,
+        _ => ErrorClass::Other,
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum AppError {
+    #[error("{0:#}")]
+    Config(AnyError),
+
+    /// An error of a kernel.flavor, and the config key it comes from
+    #[error("{entry}{}: {source}", key.as_ref().map(|k| format!(" ({})", k)).unwrap_or_default())]
+    Entry {
+        entry: String,
+        key: Option<String>,
+        source: Box<AppError>,
+    },
+
     #[error("Splash image is not a valid bmp file")]
     InvalidSplash,
 
@@ -30,6 +80,80 @@ pub enum AppError {
         source: std::io::Error,
     },
 
+    #[error("Invalid format: {0}")]
+    Template(FormatError),
+
+    #[error("Invalid cmdline: {0}")]
+    Cmdline(CmdlineError),
+
+    #[error("No valid dm-verity root hash in \"{}\"", .0.to_string_lossy())]
+    InvalidRootHash(PathBuf),
+
+    #[error("No output configured")]
+    MissingOutput,
+
     #[error("Found multiple microcode images (intel-ucode, amd-ucode)")]
     MultipleMicrocode,
+
+    #[error("{tool} failed: {reason}")]
+    Tool { tool: String, reason: String },
+
+    #[error("{failed} of {total} entries failed")]
+    Failed {
+        failed: usize,
+        total: usize,
+        class: ErrorClass,
+    },
+}
+
+impl AppError {
+    pub fn class(&self) -> ErrorClass {
+        match self {
+            AppError::Entry { source, .. } => source.class(),
+            AppError::Config(error) => error
+                .chain()
+                .find_map(|e| match e.downcast_ref::<glob::GlobError>() {
+                    Some(e) => Some(e.error()),
+                    None => e.downcast_ref::<std::io::Error>(),
+                })
+                .map_or(ErrorClass::Config, io_class),
+            AppError::Template(_) | AppError::Cmdline(_) | AppError::MissingOutput => {
+                ErrorClass::Config
+            }
+            AppError::IoError { source, .. } => io_class(source),
+            AppError::InvalidSplash
+            | AppError::InvalidRootHash(_)
+            | AppError::MultipleMicrocode => ErrorClass::InvalidInput,
+            AppError::Tool { .. } => ErrorClass::Tool,
+            AppError::Failed { class, .. } => *class,
+        }
+    }
+
+    /// Error loading the config, those of genuki itself are kept as they are
+    pub fn from_config(error: AnyError) -> Self {
+        match error.downcast::<AppError>() {
+            Ok(error) => error,
+            Err(error) => AppError::Config(error),
+        }
+    }
+
+    /// Tag an error with the entry (and key) it comes from, unless it already is
+    pub fn context(self, entry: impl Into<String>, key: Option<&str>) -> Self {
+        match self {
+            AppError::Entry { .. } => self,
+            _ => AppError::Entry {
+                entry: entry.into(),
+                key: key.map(str::to_owned),
+                source: Box::new(self),
+            },
+        }
+    }
+
+    /// The error itself, without the entry it comes from
+    pub fn detail(&self) -> &AppError {
+        match self {
+            AppError::Entry { source, .. } => source.detail(),
+            _ => self,
+        }
+    }
 }
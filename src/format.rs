@@ -15,32 +15,133 @@
 // You should have received a copy of the GNU General Public License
 // along with genuki.  If not, see <http://www.gnu.org/licenses/>.
 
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 
 use serde::Deserialize;
+use thiserror::Error;
+
+use crate::system;
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct FormatPath(String);
 
-impl FormatPath {
-    pub fn replace(&self, kernel: &str, flavor: &str) -> PathBuf {
-        let mut format = self.0.clone();
+/// Values for the placeholders of a format, e.g. "kernel" for {kernel}
+pub type Vars = HashMap<String, String>;
+
+/// Placeholders genuki knows about, besides template captures and 'env:NAME'
+const KNOWN: &[&str] = &[
+    "kernel",
+    "flavor",
+    "version",
+    "arch",
+    "machine-id",
+    "os-id",
+    "esp",
+    "hostname",
+    "root-uuid",
+    "root-partuuid",
+    "rootflags",
+    "resume-uuid",
+    "resume-offset",
+];
+
+#[derive(Debug, Error)]
+pub enum FormatError {
+    #[error("unknown placeholder {{{0}}}")]
+    Unknown(String),
+
+    #[error("placeholder {{{0}}} has no value here, consider a default like {{{0}:-value}}")]
+    Unavailable(String),
 
-        let placeholders = ["{kernel}", "{flavor}"];
+    #[error("unclosed placeholder in \"{0}\", use '{{{{' for a literal '{{'")]
+    Unclosed(String),
+
+    #[error("unexpected '}}' in \"{0}\", use '}}}}' for a literal '}}'")]
+    UnexpectedBrace(String),
+}
+
+// Values that come from the running system, only looked up when used
+fn system_var(name: &str) -> Option<String> {
+    match name {
+        "arch" => Some(std::env::consts::ARCH.into()),
+        "machine-id" => system::machine_id(),
+        "os-id" => system::os_id(),
+        "esp" => system::esp().map(|esp| esp.to_string_lossy().to_string()),
+        "hostname" => system::hostname(),
+        "root-uuid" => system::root_uuid(),
+        "root-partuuid" => system::root_partuuid(),
+        "rootflags" => system::rootflags(),
+        "resume-uuid" => system::resume_uuid(),
+        "resume-offset" => system::resume_offset(),
+        _ => None,
+    }
+}
+
+fn lookup(name: &str, vars: &Vars) -> Result<String, FormatError> {
+    let value = match name.strip_prefix("env:") {
+        Some(env) => std::env::var(env).ok(),
+        None => vars.get(name).cloned().or_else(|| system_var(name)),
+    };
+
+    match value {
+        Some(value) => Ok(value),
+        None if name.starts_with("env:") || KNOWN.contains(&name) => {
+            Err(FormatError::Unavailable(name.into()))
+        }
+        None => Err(FormatError::Unknown(name.into())),
+    }
+}
+
+/// Replace every placeholder in 'format'.
+///
+/// Placeholders look like '{name}' or '{name:-default}', and '{{' and '}}'
+/// stand for literal braces.
+pub fn render(format: &str, vars: &Vars) -> Result<String, FormatError> {
+    let mut result = String::with_capacity(format.len());
+    let mut chars = format.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                result.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                result.push('}');
+            }
+            '}' => return Err(FormatError::UnexpectedBrace(format.into())),
+            '{' => {
+                let mut placeholder = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => placeholder.push(c),
+                        None => return Err(FormatError::Unclosed(format.into())),
+                    }
+                }
 
-        for placeholder in &placeholders {
-            if format.contains(placeholder) {
-                let value = match *placeholder {
-                    "{kernel}" => kernel,
-                    "{flavor}" => flavor,
-                    _ => unimplemented!(),
+                let value = match placeholder.split_once(":-") {
+                    Some((name, default)) => match lookup(name, vars) {
+                        Err(FormatError::Unavailable(_)) => default.into(),
+                        value => value?,
+                    },
+                    None => lookup(&placeholder, vars)?,
                 };
 
-                format = format.replace(placeholder, value);
+                result.push_str(&value);
             }
+            c => result.push(c),
         }
+    }
+
+    Ok(result)
+}
 
-        format.into()
+impl FormatPath {
+    pub fn render(&self, vars: &Vars) -> Result<PathBuf, FormatError> {
+        render(&self.0, vars).map(PathBuf::from)
     }
 }
 
@@ -55,3 +156,85 @@ impl From<String> for FormatPath {
         Self(string)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars() -> Vars {
+        let mut vars = Vars::new();
+        vars.insert("kernel".into(), "linux-lts".into());
+        vars.insert("flavor".into(), "fallback".into());
+        vars
+    }
+
+    #[test]
+    fn render_placeholders() {
+        assert_eq!(
+            render("/boot/{kernel}-{flavor}.efi", &vars()).unwrap(),
+            "/boot/linux-lts-fallback.efi"
+        );
+        assert_eq!(
+            render("no placeholders", &vars()).unwrap(),
+            "no placeholders"
+        );
+        assert_eq!(render("", &vars()).unwrap(), "");
+    }
+
+    #[test]
+    fn render_escaped_braces() {
+        assert_eq!(render("{{kernel}}", &vars()).unwrap(), "{kernel}");
+        assert_eq!(render("{{{kernel}}}", &vars()).unwrap(), "{linux-lts}");
+        assert_eq!(render("a}}b{{c", &vars()).unwrap(), "a}b{c");
+    }
+
+    #[test]
+    fn render_defaults() {
+        assert_eq!(render("{kernel:-none}", &vars()).unwrap(), "linux-lts");
+        assert_eq!(render("{version:-unknown}", &vars()).unwrap(), "unknown");
+        assert_eq!(render("{version:-}", &vars()).unwrap(), "");
+        assert!(matches!(
+            render("{nope:-x}", &vars()),
+            Err(FormatError::Unknown(name)) if name == "nope"
+        ));
+    }
+
+    #[test]
+    fn render_env() {
+        std::env::set_var("GENUKI_FORMAT_TEST", "value");
+        std::env::remove_var("GENUKI_FORMAT_TEST_UNSET");
+
+        assert_eq!(
+            render("{env:GENUKI_FORMAT_TEST}", &vars()).unwrap(),
+            "value"
+        );
+        assert_eq!(
+            render("{env:GENUKI_FORMAT_TEST_UNSET:-default}", &vars()).unwrap(),
+            "default"
+        );
+        assert!(matches!(
+            render("{env:GENUKI_FORMAT_TEST_UNSET}", &vars()),
+            Err(FormatError::Unavailable(name)) if name == "env:GENUKI_FORMAT_TEST_UNSET"
+        ));
+    }
+
+    #[test]
+    fn render_errors() {
+        assert!(matches!(
+            render("{nope}", &vars()),
+            Err(FormatError::Unknown(name)) if name == "nope"
+        ));
+        assert!(matches!(
+            render("{version}", &vars()),
+            Err(FormatError::Unavailable(name)) if name == "version"
+        ));
+        assert!(matches!(
+            render("/boot/{kernel", &vars()),
+            Err(FormatError::Unclosed(_))
+        ));
+        assert!(matches!(
+            render("/boot/kernel}", &vars()),
+            Err(FormatError::UnexpectedBrace(_))
+        ));
+    }
+}
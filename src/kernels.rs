@@ -0,0 +1,261 @@
+// Copyright (C) 2020 Kevin Dc
+//
+// This file is part of genuki.
+//
+// genuki is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// genuki is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with genuki.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::cmp::Ordering::{self, Equal, Less};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use anyhow::Error;
+
+const MODULES_DIR: &str = "/usr/lib/modules";
+const BOOT_DIR: &str = "/boot";
+
+/// A kernel installed in the system
+#[derive(Debug, Clone)]
+pub struct InstalledKernel {
+    /// Name used in the config, the package name (pkgbase) when known
+    pub name: String,
+    /// Kernel release, i.e. the name of its modules directory
+    pub release: Option<String>,
+    pub image: PathBuf,
+}
+
+// Kernels installed the modern way, '/usr/lib/modules/<release>/vmlinuz',
+// named after their 'pkgbase' file (Arch), or after their release (Fedora)
+fn from_modules(modules_dir: &Path) -> Result<Vec<InstalledKernel>, Error> {
+    let mut kernels: Vec<InstalledKernel> = Vec::new();
+    for image in glob::glob(&modules_dir.join("*/vmlinuz").to_string_lossy())? {
+        let image = image?;
+        let dir = match image.parent() {
+            Some(dir) => dir,
+            None => continue,
+        };
+
+        let release = match dir.file_name() {
+            Some(release) => release.to_string_lossy().to_string(),
+            None => continue,
+        };
+
+        let name = std::fs::read_to_string(dir.join("pkgbase"))
+            .map(|pkgbase| pkgbase.trim().to_owned())
+            .ok()
+            .filter(|pkgbase| !pkgbase.is_empty())
+            .unwrap_or_else(|| release.clone());
+
+        let kernel = InstalledKernel {
+            name,
+            release: Some(release),
+            image,
+        };
+
+        // Old releases of a package can linger (e.g. kept until a reboot),
+        // the newest one is the one to build
+        match kernels.iter_mut().find(|k| k.name == kernel.name) {
+            Some(other) => {
+                let mut older = kernel;
+                if compare_releases(&release_of(other), &release_of(&older)) == Less {
+                    std::mem::swap(other, &mut older);
+                }
+
+                log::warn!(
+                    "Ignoring release {} of {}, {} is newer",
+                    release_of(&older),
+                    older.name,
+                    release_of(other)
+                );
+            }
+            None => kernels.push(kernel),
+        }
+    }
+
+    Ok(kernels)
+}
+
+fn release_of(kernel: &InstalledKernel) -> String {
+    kernel.release.clone().unwrap_or_default()
+}
+
+// Compares releases like 'sort -V' does, e.g. 6.10.3 is newer than 6.1.5
+fn compare_releases(a: &str, b: &str) -> Ordering {
+    // Runs of digits and runs of everything else
+    let chunks = |release: &str| {
+        let bytes = release.as_bytes();
+        let mut chunks = Vec::new();
+        let mut start = 0;
+        for i in 1..=bytes.len() {
+            if i == bytes.len() || bytes[i].is_ascii_digit() != bytes[i - 1].is_ascii_digit() {
+                chunks.push(release[start..i].to_owned());
+                start = i;
+            }
+        }
+
+        chunks
+    };
+
+    let (a, b) = (chunks(a), chunks(b));
+    for (a, b) in a.iter().zip(&b) {
+        let ordering = match (a.parse::<u64>(), b.parse::<u64>()) {
+            (Ok(a), Ok(b)) => a.cmp(&b),
+            _ => a.cmp(b),
+        };
+
+        if ordering != Equal {
+            return ordering;
+        }
+    }
+
+    a.len().cmp(&b.len())
+}
+
+// Kernels installed in '/boot/vmlinuz-<name>'
+fn from_boot(boot_dir: &Path) -> Result<Vec<InstalledKernel>, Error> {
+    let mut kernels = Vec::new();
+    for image in glob::glob(&boot_dir.join("vmlinuz-*").to_string_lossy())? {
+        let image = image?;
+        let name = image.file_name().map(|n| n.to_string_lossy().to_string());
+
+        if let Some(name) = name.as_deref().and_then(|n| n.strip_prefix("vmlinuz-")) {
+            kernels.push(InstalledKernel {
+                name: name.to_owned(),
+                release: None,
+                image,
+            });
+        }
+    }
+
+    Ok(kernels)
+}
+
+/// Every installed kernel, those found in the modules directory take
+/// precedence over the ones in /boot with the same name
+pub fn installed() -> Result<Vec<InstalledKernel>, Error> {
+    let mut kernels = from_modules(Path::new(MODULES_DIR))?;
+
+    for kernel in from_boot(Path::new(BOOT_DIR))? {
+        if !kernels.iter().any(|k| k.name == kernel.name) {
+            kernels.push(kernel);
+        }
+    }
+
+    kernels.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(kernels)
+}
+
+/// Kernel release embedded in a bzImage, see the x86 boot protocol:
+/// 'HdrS' magic at 0x202 and a pointer to the version string at 0x20e
+pub fn image_release(image: &Path) -> Option<String> {
+    let mut file = File::open(image).ok()?;
+    let mut header = [0; 0x210];
+    file.read_exact(&mut header).ok()?;
+
+    if &header[0x202..0x206] != b"HdrS" {
+        return None;
+    }
+
+    let offset = u16::from_le_bytes([header[0x20e], header[0x20f]]) as u64 + 0x200;
+    let mut version = [0; 256];
+    file.seek(SeekFrom::Start(offset)).ok()?;
+    let read = file.read(&mut version).ok()?;
+
+    // e.g. "6.10.3-arch1-1 (linux@archlinux) #1 SMP PREEMPT_DYNAMIC ..."
+    let version = &version[..read];
+    let end = version.iter().position(|&b| b == 0).unwrap_or(read);
+    let version = String::from_utf8_lossy(&version[..end]);
+    version.split_whitespace().next().map(str::to_owned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cmp::Ordering::Greater;
+
+    fn bzimage(version: &[u8]) -> Vec<u8> {
+        let mut image = vec![0; 0x400];
+        image[0x202..0x206].copy_from_slice(b"HdrS");
+        image[0x20e..0x210].copy_from_slice(&0x200u16.to_le_bytes());
+        image.extend_from_slice(version);
+        image
+    }
+
+    #[test]
+    fn release_from_bzimage() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("vmlinuz");
+        let release = |contents: &[u8]| {
+            std::fs::write(&path, contents).unwrap();
+            image_release(&path)
+        };
+
+        let version = b"6.10.3-arch1-1 (linux@archlinux) #1 SMP PREEMPT_DYNAMIC\0junk";
+        let expected = Some("6.10.3-arch1-1".to_string());
+        assert_eq!(release(&bzimage(version)), expected);
+
+        // Truncated before the header, and before the version string
+        assert_eq!(release(&bzimage(version)[..0x208]), None);
+        assert_eq!(release(&bzimage(version)[..0x300]), None);
+
+        let mut not_bzimage = bzimage(version);
+        not_bzimage[0x202..0x206].copy_from_slice(b"\x7fELF");
+        assert_eq!(release(&not_bzimage), None);
+        assert_eq!(release(b""), None);
+    }
+
+    #[test]
+    fn releases_compare_by_version() {
+        assert_eq!(compare_releases("6.10.3-arch1-1", "6.1.5-arch1-1"), Greater);
+        assert_eq!(compare_releases("6.1.5-arch1-1", "6.1.5-arch1-2"), Less);
+        assert_eq!(compare_releases("6.1.5", "6.1.5-arch1-1"), Less);
+        assert_eq!(compare_releases("6.9.0-200.fc40", "6.9.0-200.fc40"), Equal);
+    }
+
+    #[test]
+    fn newest_release_of_each_pkgbase() {
+        let dir = tempfile::tempdir().unwrap();
+        for (release, pkgbase) in &[
+            ("6.1.5-arch1-1", Some("linux")),
+            ("6.10.3-arch1-1", Some("linux")),
+            ("6.6.40-1-lts", Some("linux-lts")),
+            ("6.9.0-200.fc40.x86_64", None),
+        ] {
+            let dir = dir.path().join(release);
+            std::fs::create_dir(&dir).unwrap();
+            std::fs::write(dir.join("vmlinuz"), "").unwrap();
+            if let Some(pkgbase) = pkgbase {
+                std::fs::write(dir.join("pkgbase"), format!("{}\n", pkgbase)).unwrap();
+            }
+        }
+
+        let mut kernels: Vec<_> = from_modules(dir.path())
+            .unwrap()
+            .into_iter()
+            .map(|k| (k.name.clone(), release_of(&k)))
+            .collect();
+        kernels.sort();
+
+        let expected = [
+            ("6.9.0-200.fc40.x86_64", "6.9.0-200.fc40.x86_64"),
+            ("linux", "6.10.3-arch1-1"),
+            ("linux-lts", "6.6.40-1-lts"),
+        ];
+        let expected: Vec<_> = expected
+            .iter()
+            .map(|(name, release)| (name.to_string(), release.to_string()))
+            .collect();
+        assert_eq!(kernels, expected);
+    }
+}
@@ -15,6 +15,9 @@
 // You should have received a copy of the GNU General Public License
 // along with muso.  If not, see <http://www.gnu.org/licenses/>.
 
+use std::cell::RefCell;
+use std::sync::Mutex;
+
 use ansi_term::Color::{Blue, Cyan, Red, Yellow};
 use log::{set_logger, set_max_level, Level, LevelFilter, Log, Metadata, Record, SetLoggerError};
 
@@ -22,6 +25,52 @@ pub struct Logger;
 
 static LOGGER: Logger = Logger {};
 
+// Held while a group of messages is printed, so groups never interleave
+static OUTPUT: Mutex<()> = Mutex::new(());
+
+thread_local! {
+    // Messages of the group being recorded in this thread, (is_error, message)
+    static GROUP: RefCell<Option<Vec<(bool, String)>>> = const { RefCell::new(None) };
+}
+
+/// Run `f` holding back every message it logs, and print them all together
+/// at the end. Used to keep the output of entries built in parallel readable.
+pub fn grouped<R>(f: impl FnOnce() -> R) -> R {
+    GROUP.with(|group| *group.borrow_mut() = Some(Vec::new()));
+    let result = f();
+    let messages = GROUP.with(|group| group.borrow_mut().take().unwrap_or_default());
+
+    let _guard = OUTPUT.lock().unwrap_or_else(|e| e.into_inner());
+    for (is_error, message) in messages {
+        print(is_error, &message);
+    }
+
+    result
+}
+
+fn print(is_error: bool, message: &str) {
+    if is_error {
+        eprintln!("{}", message);
+    } else {
+        println!("{}", message);
+    }
+}
+
+fn emit(is_error: bool, message: String) {
+    let message = GROUP.with(|group| match group.borrow_mut().as_mut() {
+        Some(messages) => {
+            messages.push((is_error, message));
+            None
+        }
+        None => Some(message),
+    });
+
+    if let Some(message) = message {
+        let _guard = OUTPUT.lock().unwrap_or_else(|e| e.into_inner());
+        print(is_error, &message);
+    }
+}
+
 pub fn init_logger(verbose: u64) -> Result<(), SetLoggerError> {
     set_logger(&LOGGER).map(|_| match verbose {
         0 => set_max_level(LevelFilter::Warn),
@@ -37,10 +86,22 @@ impl Log for Logger {
 
     fn log(&self, record: &Record) {
         match record.level() {
-            Level::Info => println!("{} {}", Cyan.bold().paint("[i]"), record.args()),
-            Level::Warn => eprintln!("{} {}", Yellow.bold().paint("[w]"), record.args()),
-            Level::Error => eprintln!("{} {}", Red.bold().paint("[e]"), record.args()),
-            Level::Debug => println!("{} {}", Blue.bold().paint("[d]"), record.args()),
+            Level::Info => emit(
+                false,
+                format!("{} {}", Cyan.bold().paint("[i]"), record.args()),
+            ),
+            Level::Warn => emit(
+                true,
+                format!("{} {}", Yellow.bold().paint("[w]"), record.args()),
+            ),
+            Level::Error => emit(
+                true,
+                format!("{} {}", Red.bold().paint("[e]"), record.args()),
+            ),
+            Level::Debug => emit(
+                false,
+                format!("{} {}", Blue.bold().paint("[d]"), record.args()),
+            ),
             _ => {}
         }
     }
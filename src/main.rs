@@ -16,16 +16,26 @@
 // along with gen-uki.  If not, see <http://www.gnu.org/licenses/>.
 
 mod app;
+mod check;
+mod cmdline;
 mod config;
 mod error;
 mod format;
+mod kernels;
 mod logger;
+mod manifest;
+mod os_release;
+mod system;
 mod temp;
+mod uki;
 
 use anyhow::Error;
-use clap::{App as ClapApp, Arg};
+use clap::{App as ClapApp, AppSettings, Arg, SubCommand};
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
 
 use crate::app::App;
+use crate::error::AppError;
 use crate::logger::init_logger;
 
 const NAME: &str = env!("CARGO_PKG_NAME");
@@ -38,11 +48,30 @@ fn run(app: ClapApp) -> Result<(), Error> {
     let verbose = matches.occurrences_of("verbose");
 
     init_logger(verbose).unwrap();
+    temp::keep(matches.is_present("keep-temp"));
+
+    // Also removes them when a panic unwinds through here
+    let _temp = temp::Guard;
+
+    // Don't leave copies of the initrd and such behind when interrupted,
+    // and exit like a shell would report the signal (e.g. 143 for SIGTERM)
+    let mut signals = Signals::new([SIGINT, SIGTERM, SIGHUP])?;
+    std::thread::spawn(move || {
+        if let Some(signal) = signals.forever().next() {
+            temp::cleanup();
+            std::process::exit(128 + signal);
+        }
+    });
+
+    if matches.subcommand_matches("check").is_some() {
+        log::debug!("Checking config");
+        return check::run(&matches);
+    }
 
     log::debug!("Reading config");
     let app = App::from_matches(matches)?;
     log::debug!("Parsed app: {:#?}", &app);
-    app.run()
+    Ok(app.run()?)
 }
 
 fn main() {
@@ -50,11 +79,13 @@ fn main() {
         .version(VERSION)
         .author(AUTHORS)
         .about(ABOUT)
+        .setting(AppSettings::SubcommandsNegateReqs)
         .arg(
             Arg::with_name("verbose")
                 .short("v")
                 .long("verbose")
                 .multiple(true)
+                .global(true)
                 .help("Set verbosity level (multiple)"),
         )
         .arg(
@@ -62,8 +93,17 @@ fn main() {
                 .short("c")
                 .long("config")
                 .value_name("FILE")
-                .default_value("/etc/genuki/config.yaml")
-                .help("Set custom config file"),
+                .default_value(config::DEFAULT_CONFIG)
+                .global(true)
+                .help("Set custom config file (vendor drop-ins are only read for the default)"),
+        )
+        .arg(
+            Arg::with_name("state-dir")
+                .long("state-dir")
+                .value_name("DIR")
+                .default_value("/var/lib/genuki")
+                .global(true)
+                .help("Set directory where genuki keeps track of generated images"),
         )
         .arg(
             Arg::with_name("remove")
@@ -71,6 +111,38 @@ fn main() {
                 .long("remove")
                 .help("Remove images instead of generating them"),
         )
+        .arg(
+            Arg::with_name("dry-run")
+                .short("n")
+                .long("dry-run")
+                .help("Print what would be done without writing anything"),
+        )
+        .arg(
+            Arg::with_name("force")
+                .short("f")
+                .long("force")
+                .help("Rebuild images even if their inputs didn't change"),
+        )
+        .arg(
+            Arg::with_name("keep-going")
+                .short("k")
+                .long("keep-going")
+                .help("Go on with the other entries when one fails, and summarize at the end"),
+        )
+        .arg(
+            Arg::with_name("jobs")
+                .short("j")
+                .long("jobs")
+                .value_name("N")
+                .default_value("1")
+                .validator(|n| n.parse::<usize>().map(|_| ()).map_err(|e| e.to_string()))
+                .help("Build up to N entries in parallel (0 for one per CPU)"),
+        )
+        .arg(
+            Arg::with_name("keep-temp")
+                .long("keep-temp")
+                .help("Keep the temp files used to build the images (for debugging)"),
+        )
         .arg(
             Arg::with_name("all")
                 .short("a")
@@ -85,10 +157,24 @@ fn main() {
                 .index(1)
                 .required_unless("all")
                 .help("Generate UKIs for the specified regexes"),
+        )
+        .subcommand(
+            SubCommand::with_name("list")
+                .about("List every configured kernel.flavor with its resolved paths"),
+        )
+        .subcommand(
+            SubCommand::with_name("check")
+                .about("Validate the config file and every entry it defines"),
         );
 
     if let Err(e) = run(app) {
         log::error!("{}", e);
-        std::process::exit(1);
+
+        // Documented in the README, anything unexpected is a 1
+        let code = e
+            .downcast_ref::<AppError>()
+            .map_or(1, |e| e.class().exit_code());
+
+        std::process::exit(code);
     }
 }
@@ -0,0 +1,285 @@
+// Copyright (C) 2020 Kevin Dc
+//
+// This file is part of genuki.
+//
+// genuki is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// genuki is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with genuki.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::error::AppError;
+use crate::uki::{Plan, Source};
+
+fn hash_file(hasher: &mut Sha256, path: &Path) -> Result<(), AppError> {
+    let io_error = |e| AppError::IoError {
+        path: path.into(),
+        source: e,
+    };
+
+    let mut file = File::open(path).map_err(io_error)?;
+    std::io::copy(&mut file, hasher).map_err(io_error)?;
+    Ok(())
+}
+
+fn hash_source(source: &Source) -> Result<String, AppError> {
+    let mut hasher = Sha256::new();
+    match source {
+        Source::File(path) => hash_file(&mut hasher, path)?,
+        Source::Concat(paths) => {
+            for path in paths {
+                hash_file(&mut hasher, path)?;
+            }
+        }
+        Source::Generated { contents, .. } => hasher.update(contents),
+    }
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+fn hash_path(path: &Path) -> Result<String, AppError> {
+    let mut hasher = Sha256::new();
+    hash_file(&mut hasher, path)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// One of the files (or generated contents) embedded in an image
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Input {
+    /// Files the contents came from, empty if generated by genuki
+    pub paths: Vec<PathBuf>,
+    pub sha256: String,
+}
+
+impl Input {
+    fn from_source(source: &Source) -> Result<Self, AppError> {
+        let paths = match source {
+            Source::File(path) => vec![path.clone()],
+            Source::Concat(paths) => paths.clone(),
+            Source::Generated { .. } => Vec::new(),
+        };
+
+        Ok(Self {
+            paths,
+            sha256: hash_source(source)?,
+        })
+    }
+}
+
+/// Every input of an image, keyed by section name
+pub type Inputs = BTreeMap<String, Input>;
+
+pub fn inputs(plan: &Plan) -> Result<Inputs, AppError> {
+    let mut inputs = Inputs::new();
+    inputs.insert(
+        "efistub".into(),
+        Input::from_source(&Source::File(plan.efistub.clone()))?,
+    );
+
+    for section in &plan.sections {
+        inputs.insert(
+            format!(".{}", section.name),
+            Input::from_source(&section.source)?,
+        );
+    }
+
+    Ok(inputs)
+}
+
+/// What genuki knows about an image it wrote
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Record {
+    /// The kernel.flavor that produced it
+    pub entry: String,
+    /// Seconds since the UNIX epoch
+    pub built: u64,
+    pub inputs: Inputs,
+    pub sha256: String,
+}
+
+impl Record {
+    pub fn new(entry: String, output: &Path, inputs: Inputs) -> Result<Self, AppError> {
+        let built = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+
+        Ok(Self {
+            entry,
+            built,
+            inputs,
+            sha256: hash_path(output)?,
+        })
+    }
+
+    /// Output is still the one we wrote, and was built from exactly the same inputs
+    pub fn is_fresh(&self, output: &Path, inputs: &Inputs) -> bool {
+        &self.inputs == inputs && hash_path(output).is_ok_and(|sha256| sha256 == self.sha256)
+    }
+}
+
+/// Persistent record of every image generated by genuki
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    #[serde(skip)]
+    path: PathBuf,
+    outputs: BTreeMap<PathBuf, Record>,
+}
+
+impl Manifest {
+    pub fn load(state_dir: impl AsRef<Path>) -> Self {
+        let path = state_dir.as_ref().join("manifest.json");
+
+        // A missing or broken manifest only means that everything gets rebuilt
+        let mut manifest = match std::fs::read(&path) {
+            Ok(contents) => serde_json::from_slice(&contents).unwrap_or_else(|e| {
+                log::warn!("Ignoring invalid {}: {}", path.to_string_lossy(), e);
+                Self::default()
+            }),
+            Err(_) => Self::default(),
+        };
+
+        manifest.path = path;
+        manifest
+    }
+
+    // Outputs in the config can be relative, don't mix up the same relative
+    // path used from different directories
+    fn key(output: &Path) -> PathBuf {
+        std::path::absolute(output).unwrap_or_else(|_| output.into())
+    }
+
+    pub fn get(&self, output: &Path) -> Option<&Record> {
+        self.outputs.get(&Self::key(output))
+    }
+
+    pub fn insert(&mut self, output: &Path, record: Record) {
+        self.outputs.insert(Self::key(output), record);
+    }
+
+    pub fn forget(&mut self, output: &Path) {
+        self.outputs.remove(&Self::key(output));
+    }
+
+    pub fn save(&self) -> Result<(), AppError> {
+        let io_error = |e| AppError::IoError {
+            path: self.path.clone(),
+            source: e,
+        };
+
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent).map_err(io_error)?;
+        }
+
+        // Write and rename, so an interrupted run never leaves a truncated file
+        let contents = serde_json::to_vec_pretty(self).expect("Serializing can't fail");
+        let temp_path = self.path.with_extension("json.new");
+        std::fs::write(&temp_path, contents).map_err(io_error)?;
+        std::fs::rename(&temp_path, &self.path).map_err(io_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build(dir: &Path) -> (PathBuf, Inputs) {
+        let linux = dir.join("vmlinuz");
+        let output = dir.join("linux.efi");
+        std::fs::write(&linux, "kernel").unwrap();
+        std::fs::write(&output, "image").unwrap();
+
+        let mut inputs = Inputs::new();
+        inputs.insert(
+            ".linux".into(),
+            Input::from_source(&Source::File(linux)).unwrap(),
+        );
+
+        (output, inputs)
+    }
+
+    fn is_fresh(manifest: &Manifest, output: &Path, inputs: &Inputs) -> bool {
+        manifest
+            .get(output)
+            .is_some_and(|record| record.is_fresh(output, inputs))
+    }
+
+    #[test]
+    fn unchanged_inputs_are_fresh() {
+        let dir = tempfile::tempdir().unwrap();
+        let (output, inputs) = build(dir.path());
+
+        let mut manifest = Manifest::load(dir.path());
+        assert!(!is_fresh(&manifest, &output, &inputs));
+
+        let record = Record::new("linux.default".into(), &output, inputs.clone()).unwrap();
+        manifest.insert(&output, record);
+        manifest.save().unwrap();
+
+        let manifest = Manifest::load(dir.path());
+        assert!(is_fresh(&manifest, &output, &inputs));
+        assert_eq!(manifest.get(&output).unwrap().entry, "linux.default");
+    }
+
+    #[test]
+    fn changed_inputs_are_stale() {
+        let dir = tempfile::tempdir().unwrap();
+        let (output, inputs) = build(dir.path());
+
+        let mut manifest = Manifest::load(dir.path());
+        let record = Record::new("linux.default".into(), &output, inputs.clone()).unwrap();
+        manifest.insert(&output, record);
+
+        std::fs::write(dir.path().join("vmlinuz"), "new kernel").unwrap();
+        let source = Source::File(dir.path().join("vmlinuz"));
+        let mut changed = inputs;
+        changed.insert(".linux".into(), Input::from_source(&source).unwrap());
+        assert!(!is_fresh(&manifest, &output, &changed));
+    }
+
+    #[test]
+    fn modified_outputs_are_stale() {
+        let dir = tempfile::tempdir().unwrap();
+        let (output, inputs) = build(dir.path());
+
+        let mut manifest = Manifest::load(dir.path());
+        let record = Record::new("linux.default".into(), &output, inputs.clone()).unwrap();
+        manifest.insert(&output, record);
+
+        std::fs::write(&output, "signed image").unwrap();
+        assert!(!is_fresh(&manifest, &output, &inputs));
+
+        std::fs::remove_file(&output).unwrap();
+        assert!(!is_fresh(&manifest, &output, &inputs));
+    }
+
+    #[test]
+    fn corrupt_manifest_is_ignored() {
+        let dir = tempfile::tempdir().unwrap();
+        let (output, inputs) = build(dir.path());
+        std::fs::write(dir.path().join("manifest.json"), "{\"outputs\": [").unwrap();
+
+        let mut manifest = Manifest::load(dir.path());
+        assert!(manifest.get(&output).is_none());
+
+        // And gets replaced by the next save
+        let record = Record::new("linux.default".into(), &output, inputs.clone()).unwrap();
+        manifest.insert(&output, record);
+        manifest.save().unwrap();
+        assert!(is_fresh(&Manifest::load(dir.path()), &output, &inputs));
+    }
+}
@@ -0,0 +1,260 @@
+// Copyright (C) 2020 Kevin Dc
+//
+// This file is part of genuki.
+//
+// genuki is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// genuki is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with genuki.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Line {
+    /// Comments, blank lines and anything that isn't an assignment, kept as is
+    Other(String),
+    Field(String, String),
+}
+
+/// Contents of an os-release file, see os-release(5)
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OsRelease {
+    lines: Vec<Line>,
+}
+
+fn valid_key(key: &str) -> bool {
+    !key.is_empty()
+        && !key.starts_with(|c: char| c.is_ascii_digit())
+        && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// Shell-like value: quoted with "..." or '...', or unquoted with backslash
+// escapes, only the first word is taken like a shell would do
+fn unquote(value: &str) -> String {
+    let mut result = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    let mut quote = None;
+
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (Some(q), c) if c == q => quote = None,
+            (Some('\''), c) => result.push(c),
+            (_, '\\') => match chars.next() {
+                Some(next) if quote.is_none() || "\"\\$`".contains(next) => result.push(next),
+                Some(next) => {
+                    result.push('\\');
+                    result.push(next);
+                }
+                None => result.push('\\'),
+            },
+            (None, c) if c.is_whitespace() => break,
+            (_, c) => result.push(c),
+        }
+    }
+
+    result
+}
+
+// Values with anything but letters and digits are written double quoted
+fn quote(value: &str) -> String {
+    if !value.is_empty() && value.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return value.into();
+    }
+
+    let mut result = String::with_capacity(value.len() + 2);
+    result.push('"');
+    for c in value.chars() {
+        if "\"\\$`".contains(c) {
+            result.push('\\');
+        }
+        result.push(c);
+    }
+    result.push('"');
+    result
+}
+
+impl OsRelease {
+    pub fn parse(contents: &str) -> Self {
+        let lines = contents
+            .lines()
+            .map(|line| {
+                let trimmed = line.trim();
+                if trimmed.starts_with('#') {
+                    return Line::Other(line.into());
+                }
+
+                match trimmed.split_once('=') {
+                    Some((key, value)) if valid_key(key.trim()) => {
+                        Line::Field(key.trim().into(), unquote(value.trim()))
+                    }
+                    _ => Line::Other(line.into()),
+                }
+            })
+            .collect();
+
+        Self { lines }
+    }
+
+    /// Bare minimum, with the defaults os-release(5) gives for these fields
+    pub fn generic() -> Self {
+        let mut os_release = Self::default();
+        os_release.set("NAME", "Linux");
+        os_release.set("ID", "linux");
+        os_release.set("PRETTY_NAME", "Linux");
+        os_release
+    }
+
+    /// Value of a field, the last assignment wins like in a shell
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.lines.iter().rev().find_map(|line| match line {
+            Line::Field(k, value) if k == key => Some(value.as_str()),
+            _ => None,
+        })
+    }
+
+    /// Names of the fields, in the order they're first assigned
+    pub fn keys(&self) -> Vec<&str> {
+        let mut keys = Vec::new();
+        for line in &self.lines {
+            if let Line::Field(key, _) = line {
+                if !keys.contains(&key.as_str()) {
+                    keys.push(key.as_str());
+                }
+            }
+        }
+
+        keys
+    }
+
+    /// Replace the value of a field in place, or add it at the end
+    pub fn set(&mut self, key: &str, value: impl Into<String>) {
+        let value = value.into();
+        let existing = self.lines.iter_mut().rev().find_map(|line| match line {
+            Line::Field(k, v) if k == key => Some(v),
+            _ => None,
+        });
+
+        match existing {
+            Some(current) => *current = value,
+            None => self.lines.push(Line::Field(key.into(), value)),
+        }
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        self.lines
+            .retain(|line| !matches!(line, Line::Field(k, _) if k == key));
+    }
+}
+
+impl fmt::Display for OsRelease {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for line in &self.lines {
+            match line {
+                Line::Other(line) => writeln!(f, "{}", line)?,
+                Line::Field(key, value) => writeln!(f, "{}={}", key, quote(value))?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_quoting() {
+        let os_release = OsRelease::parse(concat!(
+            "NAME=\"Arch Linux\"\n",
+            "ID=arch\n",
+            "PRETTY_NAME='Arch Linux (rolling)'\n",
+            "VERSION=\"a \\\"quoted\\\" \\$value \\\\ \\n\"\n",
+            "BUILD_ID=rolling\\ release\n",
+            "VARIANT=first second\n",
+            "LOGO=\"a\"'b'c\n",
+        ));
+
+        assert_eq!(os_release.get("NAME"), Some("Arch Linux"));
+        assert_eq!(os_release.get("ID"), Some("arch"));
+        assert_eq!(os_release.get("PRETTY_NAME"), Some("Arch Linux (rolling)"));
+        assert_eq!(
+            os_release.get("VERSION"),
+            Some("a \"quoted\" $value \\ \\n")
+        );
+        assert_eq!(os_release.get("BUILD_ID"), Some("rolling release"));
+        assert_eq!(os_release.get("VARIANT"), Some("first"));
+        assert_eq!(os_release.get("LOGO"), Some("abc"));
+    }
+
+    #[test]
+    fn parse_comments_and_blank_lines() {
+        let contents = "# comment\n\n  # indented = comment\nID=arch\nnot an assignment\n\
+                        1ID=bad\nexport ID=bad\n  ID_LIKE = \"x\"  \n";
+        let os_release = OsRelease::parse(contents);
+
+        assert_eq!(os_release.get("ID"), Some("arch"));
+        assert_eq!(os_release.get("ID_LIKE"), Some("x"));
+        assert_eq!(os_release.get("1ID"), None);
+        assert_eq!(os_release.get("export ID"), None);
+        assert_eq!(
+            os_release.to_string(),
+            "# comment\n\n  # indented = comment\nID=arch\nnot an assignment\n\
+             1ID=bad\nexport ID=bad\nID_LIKE=x\n"
+        );
+    }
+
+    #[test]
+    fn last_assignment_wins() {
+        let mut os_release = OsRelease::parse("ID=first\nNAME=Linux\nID=second\n");
+        assert_eq!(os_release.get("ID"), Some("second"));
+
+        os_release.set("ID", "third");
+        assert_eq!(os_release.to_string(), "ID=first\nNAME=Linux\nID=third\n");
+
+        os_release.remove("ID");
+        assert_eq!(os_release.to_string(), "NAME=Linux\n");
+    }
+
+    #[test]
+    fn set_adds_missing_fields() {
+        let mut os_release = OsRelease::generic();
+        os_release.set("VERSION_ID", "6.10.3");
+        assert_eq!(
+            os_release.to_string(),
+            "NAME=Linux\nID=linux\nPRETTY_NAME=Linux\nVERSION_ID=\"6.10.3\"\n"
+        );
+    }
+
+    #[test]
+    fn quote_round_trip() {
+        for value in &[
+            "",
+            "arch",
+            "Arch Linux",
+            "a \"b\" $c `d` \\e",
+            "it's",
+            "{x}",
+        ] {
+            assert_eq!(unquote(&quote(value)), *value, "{}", value);
+
+            let mut os_release = OsRelease::default();
+            os_release.set("KEY", *value);
+            let parsed = OsRelease::parse(&os_release.to_string());
+            assert_eq!(parsed.get("KEY"), Some(*value));
+        }
+
+        assert_eq!(quote("arch"), "arch");
+        assert_eq!(quote(""), "\"\"");
+        assert_eq!(quote("a $b"), "\"a \\$b\"");
+    }
+}
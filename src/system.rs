@@ -0,0 +1,719 @@
+// Copyright (C) 2020 Kevin Dc
+//
+// This file is part of genuki.
+//
+// genuki is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// genuki is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with genuki.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::os_release::OsRelease;
+
+/// A line of /proc/self/mountinfo, only the fields we care about
+#[derive(Debug, Clone)]
+pub struct Mount {
+    pub mount_point: PathBuf,
+    pub fs_type: String,
+    pub source: String,
+    pub super_options: String,
+}
+
+/// An encrypted device holding the root filesystem
+#[derive(Debug, Clone)]
+pub struct LuksDevice {
+    /// Name of the mapping, i.e. /dev/mapper/<name>
+    pub name: String,
+    /// UUID of the LUKS header
+    pub uuid: Option<String>,
+    /// Options in crypttab (if any)
+    pub options: Option<String>,
+}
+
+/// A line of /etc/fstab
+#[derive(Debug, Clone)]
+struct FstabEntry {
+    spec: String,
+    file: String,
+    fs_type: String,
+    options: String,
+}
+
+// Mount points have spaces and such escaped as octal, e.g. '\040'
+fn unescape(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut result = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let octal = bytes.get(i + 1..i + 4).filter(|_| bytes[i] == b'\\');
+        let byte = octal
+            .and_then(|octal| std::str::from_utf8(octal).ok())
+            .filter(|octal| octal.bytes().all(|b| (b'0'..=b'7').contains(&b)))
+            .and_then(|octal| u8::from_str_radix(octal, 8).ok());
+
+        match byte {
+            Some(byte) => {
+                result.push(byte);
+                i += 4;
+            }
+            None => {
+                result.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&result).into_owned()
+}
+
+fn parse_mountinfo(mountinfo: &str) -> Vec<Mount> {
+    let mut mounts = Vec::new();
+
+    for line in mountinfo.lines() {
+        // id parent major:minor root mount-point options [optional...] - type source super-options
+        let (left, right) = match line.split_once(" - ") {
+            Some(parts) => parts,
+            None => continue,
+        };
+
+        let left: Vec<_> = left.split(' ').collect();
+        let right: Vec<_> = right.split(' ').collect();
+        if left.len() < 6 || right.len() < 3 {
+            continue;
+        }
+
+        mounts.push(Mount {
+            mount_point: unescape(left[4]).into(),
+            fs_type: right[0].into(),
+            source: unescape(right[1]),
+            super_options: right[2].into(),
+        });
+    }
+
+    mounts
+}
+
+/// Currently mounted filesystems
+pub fn mounts() -> Vec<Mount> {
+    parse_mountinfo(&std::fs::read_to_string("/proc/self/mountinfo").unwrap_or_default())
+}
+
+fn parse_fstab(fstab: &str) -> Vec<FstabEntry> {
+    fstab
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .filter_map(|line| {
+            let fields: Vec<_> = line.split_whitespace().map(unescape).collect();
+            match fields.as_slice() {
+                [spec, file, fs_type, options, ..] => Some(FstabEntry {
+                    spec: spec.clone(),
+                    file: file.clone(),
+                    fs_type: fs_type.clone(),
+                    options: options.clone(),
+                }),
+                [spec, file, fs_type] => Some(FstabEntry {
+                    spec: spec.clone(),
+                    file: file.clone(),
+                    fs_type: fs_type.clone(),
+                    options: "defaults".into(),
+                }),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+fn fstab() -> Vec<FstabEntry> {
+    parse_fstab(&std::fs::read_to_string("/etc/fstab").unwrap_or_default())
+}
+
+// Active swaps in /proc/swaps, and whether each one is a file
+fn parse_swaps(swaps: &str) -> Vec<(PathBuf, bool)> {
+    // Filename Type Size Used Priority
+    swaps
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<_> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                [name, kind, ..] => Some((PathBuf::from(unescape(name)), *kind == "file")),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+// Swap in memory (zram) is lost on hibernation, it can't be resumed from
+fn resumable(swap: &Path) -> bool {
+    !swap.to_string_lossy().starts_with("/dev/zram")
+}
+
+// Device node of a fstab spec, e.g. 'UUID=...' or '/dev/sda2'
+fn resolve_device(spec: &str) -> Option<PathBuf> {
+    let tags = [
+        ("UUID=", "by-uuid"),
+        ("PARTUUID=", "by-partuuid"),
+        ("LABEL=", "by-label"),
+        ("PARTLABEL=", "by-partlabel"),
+    ];
+
+    let path = tags
+        .iter()
+        .find_map(|(tag, dir)| {
+            let value = spec.strip_prefix(tag)?;
+            Some(Path::new("/dev/disk").join(dir).join(value))
+        })
+        .unwrap_or_else(|| spec.into());
+
+    path.canonicalize().ok()
+}
+
+// Name of the link in /dev/disk/<dir> pointing to a device, e.g. its UUID
+fn device_link(dir: &str, device: &Path) -> Option<String> {
+    let links = std::fs::read_dir(Path::new("/dev/disk").join(dir)).ok()?;
+
+    links.filter_map(Result::ok).find_map(|link| {
+        let target = link.path().canonicalize().ok()?;
+        if target == device {
+            Some(link.file_name().to_string_lossy().to_string())
+        } else {
+            None
+        }
+    })
+}
+
+// Look up a device by tag, falling back to the tag in its fstab spec, as
+// /dev/disk might not be populated (e.g. inside a chroot)
+fn device_tag(device: Option<&Path>, spec: Option<&str>, dir: &str, tag: &str) -> Option<String> {
+    device
+        .and_then(|device| device_link(dir, device))
+        .or_else(|| spec?.strip_prefix(tag).map(str::to_owned))
+}
+
+fn root_mount() -> Option<Mount> {
+    mounts()
+        .into_iter()
+        .rev()
+        .find(|m| m.mount_point.as_os_str() == "/")
+}
+
+fn root_fstab() -> Option<FstabEntry> {
+    fstab().into_iter().find(|entry| entry.file == "/")
+}
+
+// Device of the root filesystem, '/dev/root' is what the kernel shows when
+// it mounted root by itself, which isn't a real device node
+fn root_device() -> Option<PathBuf> {
+    root_mount()
+        .filter(|m| m.source.starts_with("/dev/") && m.source != "/dev/root")
+        .and_then(|m| Path::new(&m.source).canonicalize().ok())
+        .or_else(|| resolve_device(&root_fstab()?.spec))
+}
+
+/// Filesystem UUID of the root device
+pub fn root_uuid() -> Option<String> {
+    let spec = root_fstab().map(|entry| entry.spec);
+    device_tag(
+        root_device().as_deref(),
+        spec.as_deref(),
+        "by-uuid",
+        "UUID=",
+    )
+}
+
+/// Partition UUID of the root device
+pub fn root_partuuid() -> Option<String> {
+    let spec = root_fstab().map(|entry| entry.spec);
+    device_tag(
+        root_device().as_deref(),
+        spec.as_deref(),
+        "by-partuuid",
+        "PARTUUID=",
+    )
+}
+
+/// Mount options for the root filesystem: those in fstab (but 'defaults',
+/// 'rw' and 'ro') or else the btrfs subvolume it's mounted from
+pub fn rootflags() -> Option<String> {
+    let generic = ["defaults", "rw", "ro", "auto", "noauto", "nofail"];
+    let from_fstab = root_fstab().map(|entry| {
+        entry
+            .options
+            .split(',')
+            .filter(|option| !generic.contains(option))
+            .collect::<Vec<_>>()
+            .join(",")
+    });
+
+    let from_mount = || {
+        let mount = root_mount().filter(|m| m.fs_type == "btrfs")?;
+        mount
+            .super_options
+            .split(',')
+            .find(|option| option.starts_with("subvol="))
+            .map(str::to_owned)
+    };
+
+    from_fstab
+        .filter(|flags| !flags.is_empty())
+        .or_else(from_mount)
+}
+
+// Mount holding a path, the one with the longest mount point
+fn mount_of(path: &Path) -> Option<Mount> {
+    mounts()
+        .into_iter()
+        .filter(|m| path.starts_with(&m.mount_point))
+        .max_by_key(|m| m.mount_point.as_os_str().len())
+}
+
+/// Swap to resume from, the first active one on disk (or else the first
+/// one in fstab), and whether it's a file
+fn swap() -> Option<(PathBuf, bool)> {
+    let swaps = std::fs::read_to_string("/proc/swaps").unwrap_or_default();
+    let active = parse_swaps(&swaps)
+        .into_iter()
+        .find(|(swap, _)| resumable(swap));
+
+    active.or_else(|| {
+        let entry = fstab().into_iter().find(|entry| entry.fs_type == "swap")?;
+        match resolve_device(&entry.spec) {
+            Some(device) => Some((device, false)).filter(|(device, _)| resumable(device)),
+            None => {
+                let file = PathBuf::from(entry.spec);
+                let is_file = file.is_file();
+                Some((file, is_file))
+            }
+        }
+    })
+}
+
+/// Filesystem UUID of the swap partition, or of the filesystem holding
+/// the swap file
+pub fn resume_uuid() -> Option<String> {
+    let (swap, is_file) = swap()?;
+    if !is_file {
+        return device_link("by-uuid", &swap);
+    }
+
+    let mount = mount_of(&swap)?;
+    device_link("by-uuid", &Path::new(&mount.source).canonicalize().ok()?)
+}
+
+/// Physical offset (in pages) of the swap file, only for swap files
+pub fn resume_offset() -> Option<String> {
+    let (swap, is_file) = swap()?;
+    if !is_file {
+        return None;
+    }
+
+    let on_btrfs = mount_of(&swap).is_some_and(|m| m.fs_type == "btrfs");
+
+    // Extents in btrfs are logical, only btrfs itself knows the real offset
+    if on_btrfs {
+        let output = Command::new("btrfs")
+            .args(["inspect-internal", "map-swapfile", "-r"])
+            .arg(&swap)
+            .output()
+            .ok()?;
+
+        let offset = String::from_utf8_lossy(&output.stdout).trim().to_owned();
+        return Some(offset).filter(|o| output.status.success() && !o.is_empty());
+    }
+
+    // ext:     logical_offset:        physical_offset: length:   expected: flags:
+    //   0:        0..       0:      34816..     34816:      1:
+    let output = Command::new("filefrag")
+        .arg("-v")
+        .arg(&swap)
+        .output()
+        .ok()?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let first_extent = stdout
+        .lines()
+        .find(|line| line.trim_start().starts_with("0:"))?;
+    let physical = first_extent.split(':').nth(2)?;
+    let offset = physical.split("..").next()?.trim();
+
+    Some(offset.to_owned()).filter(|_| output.status.success())
+}
+
+/// Entries of a crypttab, name -> (device, options)
+type Crypttab = HashMap<String, (String, Option<String>)>;
+
+/// Where the initramfs looks for its crypttab, the first one found is used
+const CRYPTTABS: &[&str] = &["/etc/crypttab.initramfs", "/etc/crypttab"];
+
+/// sysfs directory with every block device
+const SYS_BLOCK: &str = "/sys/class/block";
+
+fn parse_crypttab(crypttab: &str) -> Crypttab {
+    // name device [keyfile [options]]
+    crypttab
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .filter_map(|line| {
+            let fields: Vec<_> = line.split_whitespace().collect();
+            let (name, device) = match fields.as_slice() {
+                [name, device, ..] => (name.to_string(), device.to_string()),
+                _ => return None,
+            };
+
+            let options = fields
+                .get(3)
+                .filter(|options| !["-", "none", ""].contains(options))
+                .map(|options| options.to_string());
+
+            Some((name, (device, options)))
+        })
+        .collect()
+}
+
+// The first of the crypttabs that exists
+fn read_crypttab(candidates: &[&Path]) -> Crypttab {
+    candidates
+        .iter()
+        .find_map(|path| std::fs::read_to_string(path).ok())
+        .map(|crypttab| parse_crypttab(&crypttab))
+        .unwrap_or_default()
+}
+
+// UUID of a crypttab device given as 'UUID=...' (or its by-uuid link), other
+// forms (e.g. '/dev/sda2') have to be looked up
+fn crypttab_uuid(device: &str) -> Option<&str> {
+    device
+        .strip_prefix("UUID=")
+        .or_else(|| device.strip_prefix("/dev/disk/by-uuid/"))
+}
+
+fn sys_block(sys_block_dir: &Path, device: &Path) -> Option<PathBuf> {
+    let name = device.file_name()?;
+    Some(sys_block_dir.join(name))
+}
+
+// Device mapper devices under (and including) a device, top to bottom,
+// e.g. an LVM volume and then the LUKS device it lives in
+fn dm_hierarchy(sys_block_dir: &Path, device: &Path) -> Vec<PathBuf> {
+    let mut hierarchy = Vec::new();
+    let mut pending = vec![device.to_path_buf()];
+
+    while let Some(device) = pending.pop() {
+        let sys = match sys_block(sys_block_dir, &device) {
+            Some(sys) if sys.join("dm").is_dir() => sys,
+            _ => continue,
+        };
+
+        if let Ok(slaves) = std::fs::read_dir(sys.join("slaves")) {
+            pending.extend(
+                slaves
+                    .filter_map(Result::ok)
+                    .map(|s| Path::new("/dev").join(s.file_name())),
+            );
+        }
+
+        hierarchy.push(sys);
+    }
+
+    hierarchy
+}
+
+// 'CRYPT-LUKS2-<uuid without dashes>-<name>' as found in dm/uuid
+fn uuid_from_dm(dm_uuid: &str) -> Option<String> {
+    let hex = dm_uuid
+        .strip_prefix("CRYPT-LUKS")?
+        .get(2..)?
+        .split('-')
+        .next()?;
+    if hex.len() != 32 {
+        return None;
+    }
+
+    Some(format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    ))
+}
+
+/// Name of the device mapper device holding root, e.g. "root" or "vg-root"
+pub fn root_mapper_name() -> Option<String> {
+    root_device()
+        .and_then(|device| {
+            let sys = sys_block(Path::new(SYS_BLOCK), &device)?;
+            read_trimmed(sys.join("dm/name"))
+        })
+        .or_else(|| {
+            let spec = root_fstab()?.spec;
+            spec.strip_prefix("/dev/mapper/").map(str::to_owned)
+        })
+}
+
+/// Encrypted devices below root, found in sysfs or else (e.g. in a chroot)
+/// from a '/dev/mapper/<name>' root in fstab listed in crypttab
+pub fn root_luks_devices() -> Vec<LuksDevice> {
+    let candidates: Vec<_> = CRYPTTABS.iter().map(Path::new).collect();
+    let crypttab = read_crypttab(&candidates);
+    let mut devices = Vec::new();
+
+    let hierarchy = root_device().map(|device| dm_hierarchy(Path::new(SYS_BLOCK), &device));
+    for sys in hierarchy.unwrap_or_default() {
+        let dm_uuid = read_trimmed(sys.join("dm/uuid")).unwrap_or_default();
+        let name = match read_trimmed(sys.join("dm/name")) {
+            Some(name) if dm_uuid.starts_with("CRYPT-LUKS") => name,
+            _ => continue,
+        };
+
+        let entry = crypttab.get(&name);
+        let uuid = entry
+            .and_then(|(device, _)| crypttab_uuid(device).map(str::to_owned))
+            .or_else(|| uuid_from_dm(&dm_uuid));
+
+        devices.push(LuksDevice {
+            name,
+            uuid,
+            options: entry.and_then(|(_, options)| options.clone()),
+        });
+    }
+
+    if devices.is_empty() {
+        let name = root_fstab()
+            .and_then(|entry| entry.spec.strip_prefix("/dev/mapper/").map(str::to_owned));
+
+        if let Some((name, (device, options))) = name.and_then(|n| crypttab.get_key_value(&n)) {
+            let uuid = crypttab_uuid(device)
+                .map(str::to_owned)
+                .or_else(|| device_link("by-uuid", &resolve_device(device)?));
+
+            devices.push(LuksDevice {
+                name: name.clone(),
+                uuid,
+                options: options.clone(),
+            });
+        }
+    }
+
+    devices
+}
+
+fn read_trimmed(path: impl AsRef<Path>) -> Option<String> {
+    std::fs::read_to_string(path)
+        .ok()
+        .map(|contents| contents.trim().to_owned())
+        .filter(|contents| !contents.is_empty())
+}
+
+pub fn machine_id() -> Option<String> {
+    read_trimmed("/etc/machine-id")
+}
+
+pub fn hostname() -> Option<String> {
+    read_trimmed("/etc/hostname").or_else(|| read_trimmed("/proc/sys/kernel/hostname"))
+}
+
+/// The ID field of the os-release of the running system
+pub fn os_id() -> Option<String> {
+    let os_release =
+        read_trimmed("/etc/os-release").or_else(|| read_trimmed("/usr/lib/os-release"))?;
+    OsRelease::parse(&os_release).get("ID").map(str::to_owned)
+}
+
+/// Mount point of the EFI system partition, in the usual locations
+pub fn esp() -> Option<PathBuf> {
+    let mounts = mounts();
+
+    ["/efi", "/boot/efi", "/boot"].iter().find_map(|candidate| {
+        mounts
+            .iter()
+            .rev()
+            .find(|m| m.mount_point.as_os_str() == *candidate)
+            .filter(|m| m.fs_type == "vfat")
+            .map(|m| m.mount_point.clone())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unescape_octal() {
+        assert_eq!(unescape(r"/mnt/my\040disk"), "/mnt/my disk");
+        assert_eq!(unescape(r"tab\011and\134backslash"), "tab\tand\\backslash");
+        assert_eq!(unescape(r"/caf\303\251"), "/café");
+        assert_eq!(unescape(r"no\escape\09\04"), r"no\escape\09\04");
+        assert_eq!(unescape(r"trailing\"), r"trailing\");
+    }
+
+    #[test]
+    fn mountinfo_lines() {
+        let mounts = parse_mountinfo(concat!(
+            "22 1 0:21 / /proc rw,nosuid shared:5 - proc proc rw\n",
+            "28 1 259:2 /@ / rw,relatime shared:1 - btrfs /dev/nvme0n1p2 rw,subvol=/@\n",
+            "40 28 259:1 / /boot/efi rw master:3 - vfat /dev/nvme0n1p1 rw,fmask=0022\n",
+            r"41 28 8:17 / /mnt/my\040disk rw - ext4 /dev/sdb1 rw",
+            "\n",
+            "garbage\n",
+        ));
+
+        assert_eq!(mounts.len(), 4);
+        assert_eq!(mounts[1].mount_point, Path::new("/"));
+        assert_eq!(mounts[1].fs_type, "btrfs");
+        assert_eq!(mounts[1].source, "/dev/nvme0n1p2");
+        assert_eq!(mounts[1].super_options, "rw,subvol=/@");
+        assert_eq!(mounts[2].mount_point, Path::new("/boot/efi"));
+        assert_eq!(mounts[3].mount_point, Path::new("/mnt/my disk"));
+    }
+
+    #[test]
+    fn fstab_lines() {
+        let entries = parse_fstab(concat!(
+            "# <file system> <dir> <type> <options> <dump> <pass>\n",
+            "\n",
+            "UUID=1234-abcd  /          ext4  rw,noatime  0 1\n",
+            "  # indented comment\n",
+            r"LABEL=data      /mnt/my\040data  xfs",
+            "\n",
+            "/swapfile       none       swap  defaults    0 0\n",
+            "incomplete line\n",
+        ));
+
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].spec, "UUID=1234-abcd");
+        assert_eq!(entries[0].file, "/");
+        assert_eq!(entries[0].options, "rw,noatime");
+        assert_eq!(entries[1].file, "/mnt/my data");
+        assert_eq!(entries[1].options, "defaults");
+        assert_eq!(entries[2].fs_type, "swap");
+    }
+
+    #[test]
+    fn swaps_lines() {
+        let swaps = parse_swaps(concat!(
+            "Filename\t\t\t\tType\t\tSize\t\tUsed\t\tPriority\n",
+            "/dev/zram0                              partition\t8388604\t\t0\t\t100\n",
+            "/dev/nvme0n1p3                          partition\t16777212\t0\t\t-2\n",
+            r"/swap/my\040file                        file      4194300   0   -3",
+            "\n",
+        ));
+
+        assert_eq!(
+            swaps,
+            [
+                (PathBuf::from("/dev/zram0"), false),
+                (PathBuf::from("/dev/nvme0n1p3"), false),
+                (PathBuf::from("/swap/my file"), true),
+            ]
+        );
+
+        let resumable: Vec<_> = swaps.iter().filter(|(s, _)| resumable(s)).collect();
+        assert_eq!(resumable[0].0, Path::new("/dev/nvme0n1p3"));
+        assert!(parse_swaps("Filename Type Size Used Priority\n").is_empty());
+    }
+
+    #[test]
+    fn crypttab_lines() {
+        let crypttab = parse_crypttab(concat!(
+            "# <name> <device> <password> <options>\n",
+            "root  UUID=0a1b2c3d-0000-4000-8000-000000000001  none  discard,no-read-workqueue\n",
+            "home  /dev/sda3  /etc/keys/home.key  -\n",
+            "swap  /dev/disk/by-uuid/0a1b2c3d-0000-4000-8000-000000000002  -  none\n",
+            "data  PARTUUID=1234\n",
+            "  # data2 /dev/sdb1\n",
+            "lonely\n",
+        ));
+
+        assert_eq!(crypttab.len(), 4);
+        let (device, options) = &crypttab["root"];
+        assert_eq!(device, "UUID=0a1b2c3d-0000-4000-8000-000000000001");
+        assert_eq!(options.as_deref(), Some("discard,no-read-workqueue"));
+        assert_eq!(crypttab["home"], ("/dev/sda3".into(), None));
+        assert_eq!(crypttab["swap"].1, None);
+        assert_eq!(crypttab["data"].1, None);
+
+        assert_eq!(
+            crypttab_uuid(&crypttab["root"].0),
+            Some("0a1b2c3d-0000-4000-8000-000000000001")
+        );
+        assert_eq!(
+            crypttab_uuid(&crypttab["swap"].0),
+            Some("0a1b2c3d-0000-4000-8000-000000000002")
+        );
+        assert_eq!(crypttab_uuid(&crypttab["home"].0), None);
+        assert_eq!(crypttab_uuid(&crypttab["data"].0), None);
+    }
+
+    #[test]
+    fn crypttab_initramfs_first() {
+        let dir = tempfile::tempdir().unwrap();
+        let initramfs = dir.path().join("crypttab.initramfs");
+        let system = dir.path().join("crypttab");
+        let candidates = [initramfs.as_path(), system.as_path()];
+
+        assert!(read_crypttab(&candidates).is_empty());
+
+        std::fs::write(&system, "root UUID=1 none luks\n").unwrap();
+        assert_eq!(read_crypttab(&candidates)["root"].0, "UUID=1");
+
+        std::fs::write(&initramfs, "root UUID=2 none luks\n").unwrap();
+        let crypttab = read_crypttab(&candidates);
+        assert_eq!(crypttab["root"].0, "UUID=2");
+        assert_eq!(crypttab.len(), 1);
+    }
+
+    #[test]
+    fn dm_uuid() {
+        assert_eq!(
+            uuid_from_dm("CRYPT-LUKS2-0a1b2c3d00004000800000000000000a-root").as_deref(),
+            Some("0a1b2c3d-0000-4000-8000-00000000000a")
+        );
+        assert_eq!(
+            uuid_from_dm("CRYPT-LUKS1-0a1b2c3d00004000800000000000000a-luks-home").as_deref(),
+            Some("0a1b2c3d-0000-4000-8000-00000000000a")
+        );
+        assert_eq!(uuid_from_dm("CRYPT-LUKS2-0a1b2c3d-root"), None);
+        assert_eq!(uuid_from_dm("LVM-abcdef"), None);
+        assert_eq!(uuid_from_dm("CRYPT-PLAIN-root"), None);
+    }
+
+    #[test]
+    fn dm_hierarchy_below_root() {
+        let sys = tempfile::tempdir().unwrap();
+        let device = |name: &str, dm: bool, slaves: &[&str]| {
+            let dir = sys.path().join(name);
+            std::fs::create_dir_all(dir.join("slaves")).unwrap();
+            if dm {
+                std::fs::create_dir(dir.join("dm")).unwrap();
+            }
+            for slave in slaves {
+                std::fs::write(dir.join("slaves").join(slave), "").unwrap();
+            }
+        };
+
+        // LVM volume (dm-1) in a LUKS device (dm-0) on a partition
+        device("dm-1", true, &["dm-0"]);
+        device("dm-0", true, &["nvme0n1p2"]);
+        device("nvme0n1p2", false, &[]);
+
+        let hierarchy = dm_hierarchy(sys.path(), Path::new("/dev/dm-1"));
+        let expected = [sys.path().join("dm-1"), sys.path().join("dm-0")];
+        assert_eq!(hierarchy, expected);
+
+        assert!(dm_hierarchy(sys.path(), Path::new("/dev/nvme0n1p2")).is_empty());
+        assert!(dm_hierarchy(sys.path(), Path::new("/dev/sda1")).is_empty());
+    }
+}
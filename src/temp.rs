@@ -15,32 +15,95 @@
 // You should have received a copy of the GNU General Public License
 // along with genuki.  If not, see <http://www.gnu.org/licenses/>.
 
-use std::fs::File;
-use std::path::{Path, PathBuf};
+use std::fs::{File, OpenOptions, Permissions};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use tempfile::TempDir;
 
 use crate::error::AppError;
 
+/// Private directory (mode 0700) for the temp files of this run, created
+/// in $TMPDIR (or /tmp) the first time it's needed
+static DIR: Mutex<Option<TempDir>> = Mutex::new(None);
+
+/// Leave the temp files behind, for debugging
+static KEEP: AtomicBool = AtomicBool::new(false);
+
+pub fn keep(keep: bool) {
+    KEEP.store(keep, Ordering::SeqCst);
+}
+
+/// Where a temp file will be, without creating anything (for dry runs)
+pub fn temp_path(name: &str) -> PathBuf {
+    match DIR.lock().unwrap_or_else(|e| e.into_inner()).as_ref() {
+        Some(dir) => dir.path().join(name),
+        None => std::env::temp_dir().join("genuki.XXXXXX").join(name),
+    }
+}
+
 pub fn temp_file(name: &str) -> Result<(PathBuf, File), AppError> {
-    let path = Path::new("/tmp").join(name);
+    let path = {
+        let mut dir = DIR.lock().unwrap_or_else(|e| e.into_inner());
+        if dir.is_none() {
+            let created = tempfile::Builder::new()
+                .prefix("genuki.")
+                .permissions(Permissions::from_mode(0o700))
+                .tempdir()
+                .map_err(|e| AppError::IoError {
+                    path: std::env::temp_dir(),
+                    source: e,
+                })?;
+
+            log::debug!("Using temp dir {}", created.path().to_string_lossy());
+            *dir = Some(created);
+        }
+
+        dir.as_ref().unwrap().path().join(name)
+    };
 
-    log::debug!("Creating temp file: /tmp/{}", name);
-    if path.exists() {
-        let remove = if path.is_dir() {
-            std::fs::remove_dir
-        } else {
-            std::fs::remove_file
-        };
+    log::debug!("Creating temp file: {}", path.to_string_lossy());
 
-        remove(&path).map_err(|e| AppError::IoError {
+    // Names are unique per run, an existing file means something is wrong
+    let file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)
+        .map_err(|e| AppError::IoError {
             path: path.clone(),
             source: e,
         })?;
+
+    Ok((path, file))
+}
+
+/// Calls cleanup() when dropped, so it also runs when unwinding from a panic
+pub struct Guard;
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        cleanup();
     }
+}
 
-    let file = File::create(&path).map_err(|e| AppError::IoError {
-        path: path.clone(),
-        source: e,
-    })?;
+/// Remove the temp dir (unless asked to keep it), called on exit and signals
+pub fn cleanup() {
+    let dir = match DIR.lock().unwrap_or_else(|e| e.into_inner()).take() {
+        Some(dir) => dir,
+        None => return,
+    };
 
-    Ok((path, file))
+    if KEEP.load(Ordering::SeqCst) {
+        let path = dir.keep();
+        log::info!("Keeping temp files in {}", path.to_string_lossy());
+        return;
+    }
+
+    let path = dir.path().to_path_buf();
+    if let Err(e) = dir.close() {
+        log::warn!("Couldn't remove temp dir {}: {}", path.to_string_lossy(), e);
+    }
 }
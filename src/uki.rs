@@ -0,0 +1,383 @@
+// Copyright (C) 2020 Kevin Dc
+//
+// This file is part of genuki.
+//
+// genuki is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// genuki is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with genuki.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::ffi::{OsStr, OsString};
+use std::fs::OpenOptions;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use crate::config::Config;
+use crate::error::AppError;
+use crate::os_release::OsRelease;
+use crate::temp;
+
+// Whether a file can be written, without changing it (or leaving it behind
+// if it doesn't exist)
+fn writable(path: &Path) -> std::io::Result<()> {
+    if path.exists() {
+        return OpenOptions::new().write(true).open(path).map(|_| ());
+    }
+
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+
+    tempfile::tempfile_in(dir).map(|_| ())
+}
+
+// Quote an argument the way a shell would need it, for display only
+fn quote(arg: &OsStr) -> String {
+    let arg = arg.to_string_lossy();
+    let plain = |c: char| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c);
+    if !arg.is_empty() && arg.chars().all(plain) {
+        return arg.into_owned();
+    }
+
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+/// Where the contents of a section come from
+#[derive(Debug, Clone)]
+pub enum Source {
+    /// A file embedded as is
+    File(PathBuf),
+    /// Files concatenated in order (e.g. microcode + initramfs)
+    Concat(Vec<PathBuf>),
+    /// Contents generated by genuki (e.g. inline cmdline, retitled os-release),
+    /// with the file they're based on if any
+    Generated {
+        contents: Vec<u8>,
+        base: Option<PathBuf>,
+    },
+}
+
+impl Source {
+    pub fn size(&self) -> Result<u64, AppError> {
+        let file_size = |path: &PathBuf| {
+            std::fs::metadata(path)
+                .map(|m| m.len())
+                .map_err(|e| AppError::IoError {
+                    path: path.clone(),
+                    source: e,
+                })
+        };
+
+        match self {
+            Source::File(path) => file_size(path),
+            Source::Concat(paths) => paths.iter().map(file_size).sum(),
+            Source::Generated { contents, .. } => Ok(contents.len() as u64),
+        }
+    }
+
+    /// Path that will be handed to objcopy, without writing anything
+    pub fn planned_path(&self, name: &str) -> PathBuf {
+        match self {
+            Source::File(path) => path.clone(),
+            _ => temp::temp_path(name),
+        }
+    }
+
+    /// Write generated or concatenated contents to a temp file (if needed)
+    pub fn materialize(&self, name: &str) -> Result<PathBuf, AppError> {
+        let contents = match self {
+            Source::File(path) => return Ok(path.clone()),
+            Source::Generated { contents, .. } => contents.clone(),
+            Source::Concat(paths) => {
+                let mut contents = Vec::new();
+                for path in paths {
+                    let mut bytes = std::fs::read(path).map_err(|e| AppError::IoError {
+                        path: path.clone(),
+                        source: e,
+                    })?;
+
+                    contents.append(&mut bytes);
+                }
+
+                contents
+            }
+        };
+
+        let (path, mut temp) = temp::temp_file(name)?;
+        temp.write_all(&contents).map_err(|e| AppError::IoError {
+            path: path.clone(),
+            source: e,
+        })?;
+
+        Ok(path)
+    }
+
+    pub fn describe(&self) -> String {
+        match self {
+            Source::File(path) => path.to_string_lossy().to_string(),
+            Source::Concat(paths) => paths
+                .iter()
+                .map(|p| p.to_string_lossy())
+                .collect::<Vec<_>>()
+                .join(" + "),
+            Source::Generated { base, .. } => match base {
+                Some(base) => format!("generated from {}", base.to_string_lossy()),
+                None => "generated".into(),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Section {
+    pub name: &'static str,
+    pub vma: u64,
+    pub source: Source,
+}
+
+impl Section {
+    /// What genuki generated for this section, for dry runs: the whole
+    /// cmdline, or the fields of the os-release that differ from its base
+    pub fn details(&self) -> Result<Vec<String>, AppError> {
+        let (contents, base) = match &self.source {
+            Source::Generated { contents, base } => (String::from_utf8_lossy(contents), base),
+            _ => return Ok(Vec::new()),
+        };
+
+        if self.name != "osrel" {
+            return Ok(vec![contents.trim().to_owned()]);
+        }
+
+        let old = match base {
+            Some(base) => {
+                let contents = std::fs::read_to_string(base).map_err(|e| AppError::IoError {
+                    path: base.clone(),
+                    source: e,
+                })?;
+
+                OsRelease::parse(&contents)
+            }
+            None => OsRelease::default(),
+        };
+
+        let new = OsRelease::parse(&contents);
+        let mut keys = old.keys();
+        keys.extend(new.keys().into_iter().filter(|k| old.get(k).is_none()));
+
+        let mut details = Vec::new();
+        for key in keys {
+            match (old.get(key), new.get(key)) {
+                (Some(old), Some(new)) if old == new => {}
+                (old, new) => {
+                    if let Some(old) = old {
+                        details.push(format!("- {}={}", key, old));
+                    }
+                    if let Some(new) = new {
+                        details.push(format!("+ {}={}", key, new));
+                    }
+                }
+            }
+        }
+
+        Ok(details)
+    }
+}
+
+/// Everything needed to build the unified kernel image of a kernel.flavor
+#[derive(Debug, Clone)]
+pub struct Plan {
+    pub kernel: String,
+    pub flavor: String,
+    pub sections: Vec<Section>,
+    pub efistub: PathBuf,
+    pub output: PathBuf,
+}
+
+impl Plan {
+    pub fn from_config(config: &Config, kernel: &str, flavor: &str) -> Result<Self, AppError> {
+        let entry = format!("{}.{}", kernel, flavor);
+        let context = |key: &'static str| {
+            let entry = entry.clone();
+            move |e: AppError| e.context(entry, Some(key))
+        };
+
+        let mut sections = vec![Section {
+            name: "osrel",
+            vma: 0x20000,
+            source: config
+                .os_release_source(kernel, flavor)
+                .map_err(context("os-release"))?,
+        }];
+
+        if let Some(cmdline) = config
+            .cmdline_source(kernel, flavor)
+            .map_err(context("cmdline"))?
+        {
+            sections.push(Section {
+                name: "cmdline",
+                vma: 0x30000,
+                source: cmdline,
+            });
+        }
+
+        let splash_image = config
+            .splash_image_path(kernel, flavor)
+            .map_err(context("splash-image"))?;
+
+        if let Some(splash_image) = splash_image {
+            sections.push(Section {
+                name: "splash",
+                vma: 0x40000,
+                source: Source::File(splash_image),
+            });
+        }
+
+        sections.push(Section {
+            name: "linux",
+            vma: 0x2000000,
+            source: Source::File(
+                config
+                    .linux_path(kernel, flavor)
+                    .map_err(context("linux"))?,
+            ),
+        });
+
+        sections.push(Section {
+            name: "initrd",
+            vma: 0x3000000,
+            source: config
+                .initrd_source(kernel, flavor)
+                .map_err(context("initrd"))?,
+        });
+
+        Ok(Self {
+            kernel: kernel.into(),
+            flavor: flavor.into(),
+            sections,
+            efistub: config
+                .efistub_path(kernel, flavor)
+                .map_err(context("efistub"))?,
+            output: config
+                .output_path(kernel, flavor)
+                .map_err(context("output"))?,
+        })
+    }
+
+    fn temp_name(&self, section: &Section) -> String {
+        format!("{}-{}-{}", self.kernel, self.flavor, section.name)
+    }
+
+    /// Arguments for objcopy, using the already resolved path of each section
+    fn objcopy_args(&self, paths: &[PathBuf]) -> Vec<OsString> {
+        let mut args = Vec::new();
+        for (section, path) in self.sections.iter().zip(paths) {
+            let mut add_section = OsString::from(format!(".{}=", section.name));
+            add_section.push(path);
+
+            args.push("--add-section".into());
+            args.push(add_section);
+            args.push("--change-section-vma".into());
+            args.push(format!(".{}={:#x}", section.name, section.vma).into());
+        }
+
+        args.push(self.efistub.clone().into());
+        args.push(self.output.clone().into());
+        args
+    }
+
+    /// Full command line that will be run to build the image, only for display
+    pub fn command(&self, objcopy: &Path) -> String {
+        let paths: Vec<_> = self
+            .sections
+            .iter()
+            .map(|s| s.source.planned_path(&self.temp_name(s)))
+            .collect();
+
+        let mut command = vec![quote(objcopy.as_os_str())];
+        command.extend(self.objcopy_args(&paths).iter().map(|arg| quote(arg)));
+        command.join(" ")
+    }
+
+    pub fn build(&self, objcopy: &Path) -> Result<(), AppError> {
+        let mut paths = Vec::with_capacity(self.sections.len());
+        for section in &self.sections {
+            paths.push(section.source.materialize(&self.temp_name(section))?);
+        }
+
+        let args = self.objcopy_args(&paths);
+        log::debug!("Arguments for objcopy: {:#?}", &args);
+
+        let output = Command::new(objcopy)
+            .args(&args)
+            .stdin(Stdio::null())
+            .output();
+
+        let result = match output {
+            Ok(output) if output.status.success() => Ok(()),
+            Ok(output) => match String::from_utf8_lossy(&output.stderr).trim() {
+                "" => Err(output.status.to_string()),
+                stderr => Err(stderr.to_string()),
+            },
+            Err(e) => Err(e.to_string()),
+        };
+
+        if let Err(e) = result {
+            // objcopy only says it failed, tell permission problems apart
+            match writable(&self.output) {
+                Err(source) if source.kind() == ErrorKind::PermissionDenied => {
+                    return Err(AppError::IoError {
+                        path: self.output.clone(),
+                        source,
+                    })
+                }
+                _ => {}
+            }
+
+            return Err(AppError::Tool {
+                tool: "objcopy".into(),
+                reason: e,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Print the plan without touching the filesystem
+    pub fn print(&self, objcopy: &Path) -> Result<(), AppError> {
+        println!("{}.{}", self.kernel, self.flavor);
+        println!(
+            "  efistub: {} ({} bytes)",
+            self.efistub.to_string_lossy(),
+            Source::File(self.efistub.clone()).size()?
+        );
+        println!("  output:  {}", self.output.to_string_lossy());
+        println!("  sections:");
+        for section in &self.sections {
+            println!(
+                "    .{:<8} {:>#10x}  {} ({} bytes)",
+                section.name,
+                section.vma,
+                section.source.describe(),
+                section.source.size()?
+            );
+
+            for line in section.details()? {
+                println!("        {}", line);
+            }
+        }
+        println!("  command:");
+        println!("    {}", self.command(objcopy));
+
+        Ok(())
+    }
+}